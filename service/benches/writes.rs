@@ -0,0 +1,165 @@
+//! Compares the two ways ingest handlers write single-point uploads from
+//! concurrent clients: a statement per point under the shared connection lock
+//! (the baseline) and a writer task that appends queued points in one
+//! transaction per batch.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use duckdb::{params, Connection};
+use jiff::Timestamp;
+use serde_json::json;
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot, Mutex},
+};
+
+const POINTS: usize = 2_000;
+const BATCH_SIZE: usize = 1_000;
+
+type Point = (String, String);
+
+fn connection() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        r"CREATE TABLE timeseries (
+            timestamp TIMESTAMPTZ NOT NULL,
+            bucket TEXT NOT NULL,
+            payload JSON NOT NULL
+          );
+        ",
+    )
+    .unwrap();
+
+    conn
+}
+
+fn point(index: usize) -> Point {
+    let timestamp = Timestamp::from_second(1_700_000_000 + index as i64).unwrap();
+    (timestamp.to_string(), json!({ "value": index }).to_string())
+}
+
+async fn statement_per_point(clients: usize) -> Duration {
+    let connection = Arc::new(Mutex::new(connection()));
+
+    let started = Instant::now();
+    let tasks: Vec<_> = (0..clients)
+        .map(|client| {
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                for index in (client..POINTS).step_by(clients) {
+                    let (timestamp, payload) = point(index);
+                    connection
+                        .lock()
+                        .await
+                        .execute(
+                            "INSERT INTO timeseries (timestamp, bucket, payload) VALUES (?, ?, ?);",
+                            params![timestamp, "benchmark", payload],
+                        )
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    started.elapsed()
+}
+
+async fn batched_writer(clients: usize) -> Duration {
+    let (sender, mut receiver) = mpsc::channel::<(Point, oneshot::Sender<()>)>(1024);
+    let writer = tokio::task::spawn_blocking(move || {
+        let mut conn = connection();
+        while let Some(request) = receiver.blocking_recv() {
+            let mut batch = vec![request];
+            while batch.len() < BATCH_SIZE {
+                match receiver.try_recv() {
+                    Ok(request) => batch.push(request),
+                    Err(_) => break,
+                }
+            }
+
+            let tx = conn.transaction().unwrap();
+            {
+                let mut appender = tx.appender("timeseries").unwrap();
+                for ((timestamp, payload), _) in batch.iter() {
+                    appender
+                        .append_row(params![timestamp, "benchmark", payload])
+                        .unwrap();
+                }
+                appender.flush().unwrap();
+            }
+            tx.commit().unwrap();
+
+            for (_, ack) in batch {
+                let _ = ack.send(());
+            }
+        }
+    });
+
+    let started = Instant::now();
+    let tasks: Vec<_> = (0..clients)
+        .map(|client| {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                for index in (client..POINTS).step_by(clients) {
+                    let (ack, committed) = oneshot::channel();
+                    sender.send((point(index), ack)).await.unwrap();
+                    committed.await.unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let elapsed = started.elapsed();
+
+    drop(sender);
+    writer.await.unwrap();
+
+    elapsed
+}
+
+fn concurrent_writes(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("concurrent_writes");
+    group.throughput(Throughput::Elements(POINTS as u64));
+    group.sample_size(10);
+
+    for clients in [1, 16, 64] {
+        group.bench_with_input(
+            BenchmarkId::new("statement_per_point", clients),
+            &clients,
+            |b, &clients| {
+                b.iter_custom(|iterations| {
+                    (0..iterations)
+                        .map(|_| runtime.block_on(statement_per_point(clients)))
+                        .sum()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("batched_writer", clients),
+            &clients,
+            |b, &clients| {
+                b.iter_custom(|iterations| {
+                    (0..iterations)
+                        .map(|_| runtime.block_on(batched_writer(clients)))
+                        .sum()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_writes);
+criterion_main!(benches);
@@ -0,0 +1,426 @@
+use std::str::FromStr;
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use duckdb::{params_from_iter, ToSql};
+use jiff::{tz::TimeZone, Span, Timestamp, Zoned};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+use crate::{auth::AuthenticatedUser, error::AppError, AppState};
+
+/// Aggregates of a numeric payload field per interval, computed with DuckDB's
+/// `time_bucket`. Intervals without data are omitted, as are values at the
+/// path that aren't numbers.
+#[tracing::instrument(skip_all, fields( bucket = %filters.bucket))]
+pub async fn get_aggregated_data(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Query(filters): Query<AggregateFilter>,
+) -> Result<Json<Vec<AggregateResponse>>, AppError> {
+    let mut from = if let Some(f) = filters.from {
+        Timestamp::from_str(&f)
+            .map_err(AppError::DateInputError)?
+            .to_string()
+    } else {
+        Timestamp::MIN.to_string()
+    };
+
+    let mut to = if let Some(t) = filters.to {
+        Timestamp::from_str(&t)
+            .map_err(AppError::DateInputError)?
+            .to_string()
+    } else {
+        Timestamp::MAX.to_string()
+    };
+
+    if let Some(past_days) = filters.past_days {
+        to = Timestamp::now().to_string();
+        from = Zoned::now()
+            .checked_sub(Span::new().days(past_days))?
+            .timestamp()
+            .to_string();
+    }
+
+    if !filters.path.starts_with('$') {
+        return Err(AppError::InputError(format!(
+            "Invalid JSON path {}, expected e.g. $.co2",
+            filters.path
+        )));
+    }
+    let interval = parse_interval(&filters.interval)?;
+    let timezone_name = filters.timezone.unwrap_or("UTC".into());
+    let timezone = TimeZone::get(&timezone_name).map_err(AppError::DateInputError)?;
+    let functions = match filters.functions {
+        Some(functions) => functions
+            .split(',')
+            .map(|function| function.trim().parse())
+            .collect::<Result<Vec<Aggregate>, _>>()
+            .map_err(AppError::InputError)?,
+        None => vec![Aggregate::Avg],
+    };
+
+    let conn = state.connection.lock().await;
+
+    // Only alignment to other timezones needs DuckDB's ICU extension, which
+    // is downloaded on first use
+    let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(interval)];
+    let interval_start = if timezone_name == "UTC" {
+        "CAST(time_bucket(CAST((?) as INTERVAL), CAST(timestamp as TIMESTAMP)) as TIMESTAMPTZ)"
+    } else {
+        values.push(Box::new(timezone_name));
+        "time_bucket(CAST((?) as INTERVAL), timestamp, (?))"
+    };
+    values.push(Box::new(filters.path));
+    values.push(Box::new(filters.bucket));
+    values.push(Box::new(from));
+    values.push(Box::new(to));
+
+    let columns: Vec<&str> = functions.iter().map(|function| function.sql()).collect();
+    let query = format!(
+        "
+            WITH points AS (
+                SELECT {} as interval_start, timestamp, TRY_CAST(json_extract(payload, (?)) as DOUBLE) as value
+                FROM timeseries
+                WHERE bucket = (?) AND timestamp > CAST((?) as TIMESTAMP) AND timestamp < CAST((?) as TIMESTAMP)
+            )
+            SELECT cast(interval_start as Text), {}
+            FROM points
+            WHERE value IS NOT NULL
+            GROUP BY interval_start
+            ORDER BY interval_start;
+        ",
+        interval_start,
+        columns.join(", ")
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let response: Result<Vec<(String, Map<String, Value>)>, _> = stmt
+        .query_map(
+            params_from_iter(values.iter().map(|value| value.as_ref())),
+            |row| {
+                let mut values = Map::new();
+                for (index, function) in functions.iter().enumerate() {
+                    let value = match function {
+                        Aggregate::Count => Value::from(row.get::<_, i64>(index + 1)?),
+                        _ => row
+                            .get::<_, Option<f64>>(index + 1)?
+                            .and_then(Number::from_f64)
+                            .map_or(Value::Null, Value::Number),
+                    };
+                    values.insert(function.name().into(), value);
+                }
+                Ok((row.get(0)?, values))
+            },
+        )?
+        .collect();
+
+    // Interval starts are reported in the requested timezone
+    let response = response?
+        .into_iter()
+        .map(|(timestamp, values)| {
+            Ok(AggregateResponse {
+                timestamp: Timestamp::from_str(&timestamp)?
+                    .to_zoned(timezone.clone())
+                    .strftime("%Y-%m-%dT%H:%M:%S%:z")
+                    .to_string(),
+                values,
+            })
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    Ok(Json(response))
+}
+
+/// Convert an interval like `5m`, `1h`, `1d`, `1w` or `3mo` to a DuckDB interval
+fn parse_interval(interval: &str) -> Result<String, AppError> {
+    let invalid = || AppError::InputError(format!("Invalid interval {}", interval));
+
+    let split = interval
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (count, unit) = interval.split_at(split);
+    let count: u32 = count.parse().map_err(|_| invalid())?;
+    if count == 0 {
+        return Err(invalid());
+    }
+
+    let unit = match unit {
+        "m" => "minutes",
+        "h" => "hours",
+        "d" => "days",
+        "w" => "weeks",
+        "mo" => "months",
+        _ => return Err(invalid()),
+    };
+
+    Ok(format!("{} {}", count, unit))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Avg,
+    Min,
+    Max,
+    Sum,
+    Count,
+    First,
+    Last,
+    Stddev,
+}
+
+impl Aggregate {
+    fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Avg => "avg",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::Sum => "sum",
+            Aggregate::Count => "count",
+            Aggregate::First => "first",
+            Aggregate::Last => "last",
+            Aggregate::Stddev => "stddev",
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Aggregate::Avg => "avg(value)",
+            Aggregate::Min => "min(value)",
+            Aggregate::Max => "max(value)",
+            Aggregate::Sum => "sum(value)",
+            Aggregate::Count => "count(value)",
+            Aggregate::First => "arg_min(value, timestamp)",
+            Aggregate::Last => "arg_max(value, timestamp)",
+            Aggregate::Stddev => "stddev_samp(value)",
+        }
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "avg" => Ok(Aggregate::Avg),
+            "min" => Ok(Aggregate::Min),
+            "max" => Ok(Aggregate::Max),
+            "sum" => Ok(Aggregate::Sum),
+            "count" => Ok(Aggregate::Count),
+            "first" => Ok(Aggregate::First),
+            "last" => Ok(Aggregate::Last),
+            "stddev" => Ok(Aggregate::Stddev),
+            _ => Err(format!("Unknown aggregate function {}", value)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AggregateFilter {
+    bucket: String,
+    // JSON path of the aggregated field, e.g. `$.co2`
+    path: String,
+    // `5m`, `1h`, `1d`, `1w` or `1mo`
+    interval: String,
+    // comma separated, `avg` if not set
+    functions: Option<String>,
+    // IANA timezone intervals are aligned to, UTC if not set
+    timezone: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    // past_days overrides `from` and `to` params
+    past_days: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AggregateResponse {
+    // start of the interval
+    timestamp: String,
+    // one value per requested function
+    #[serde(flatten)]
+    values: Map<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Uri;
+    use duckdb::params;
+    use serde_json::json;
+
+    use super::*;
+
+    /// Readings in `co2`, including ones without a numeric value, and one in
+    /// another bucket
+    async fn test_state() -> AppState {
+        let state = AppState::in_memory().await;
+        let points = [
+            ("2024-10-01T06:10:00Z", "co2", json!({ "co2": 400 })),
+            ("2024-10-01T06:40:00Z", "co2", json!({ "co2": 500.5 })),
+            ("2024-10-01T06:50:00Z", "co2", json!({ "co2": "high" })),
+            ("2024-10-01T07:20:00Z", "co2", json!({ "co2": 600 })),
+            ("2024-10-01T07:30:00Z", "co2", json!({ "temperature": 20 })),
+            ("2024-10-01T23:30:00Z", "co2", json!({ "co2": 700 })),
+            ("2024-10-01T06:20:00Z", "climate", json!({ "co2": 9999 })),
+        ];
+        for (timestamp, bucket, payload) in points {
+            state
+                .connection
+                .lock()
+                .await
+                .execute(
+                    "INSERT INTO timeseries (timestamp, bucket, payload) VALUES (?, ?, ?);",
+                    params![timestamp, bucket, payload.to_string()],
+                )
+                .unwrap();
+        }
+
+        state
+    }
+
+    async fn aggregate(state: &AppState, query: &str) -> Value {
+        let uri: Uri = format!("/api/aggregate?bucket=co2&path=$.co2&{}", query)
+            .parse()
+            .unwrap();
+        let filters = Query::try_from_uri(&uri).unwrap();
+        let Json(response) =
+            get_aggregated_data(State(state.clone()), AuthenticatedUser {}, filters)
+                .await
+                .unwrap();
+
+        serde_json::to_value(response).unwrap()
+    }
+
+    #[test]
+    fn parses_interval_units() {
+        for (interval, expected) in [
+            ("5m", "5 minutes"),
+            ("1h", "1 hours"),
+            ("7d", "7 days"),
+            ("2w", "2 weeks"),
+            ("1mo", "1 months"),
+            ("90m", "90 minutes"),
+        ] {
+            assert_eq!(parse_interval(interval).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_intervals() {
+        for interval in [
+            "",
+            "0m",
+            "00h",
+            "5",
+            "m",
+            "mo",
+            "-1h",
+            "1.5h",
+            "5 m",
+            "5M",
+            "1y",
+            "1month",
+            "99999999999m",
+        ] {
+            assert!(parse_interval(interval).is_err(), "{}", interval);
+        }
+    }
+
+    #[test]
+    fn parses_aggregate_names() {
+        for name in [
+            "avg", "min", "max", "sum", "count", "first", "last", "stddev",
+        ] {
+            let aggregate = Aggregate::from_str(name).unwrap();
+            assert_eq!(aggregate.name(), name);
+        }
+
+        for name in ["", "AVG", "median", " avg"] {
+            assert!(Aggregate::from_str(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn aggregates_numeric_values_per_interval() {
+        let state = test_state().await;
+
+        let response = aggregate(
+            &state,
+            "interval=1h&functions=avg,min,max,sum,count,first,last",
+        )
+        .await;
+
+        assert_eq!(
+            response,
+            json!([
+                {
+                    "timestamp": "2024-10-01T06:00:00+00:00",
+                    "avg": 450.25, "min": 400.0, "max": 500.5, "sum": 900.5,
+                    "count": 2, "first": 400.0, "last": 500.5,
+                },
+                {
+                    "timestamp": "2024-10-01T07:00:00+00:00",
+                    "avg": 600.0, "min": 600.0, "max": 600.0, "sum": 600.0,
+                    "count": 1, "first": 600.0, "last": 600.0,
+                },
+                {
+                    "timestamp": "2024-10-01T23:00:00+00:00",
+                    "avg": 700.0, "min": 700.0, "max": 700.0, "sum": 700.0,
+                    "count": 1, "first": 700.0, "last": 700.0,
+                },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn aligns_intervals_to_utc_by_default() {
+        let state = test_state().await;
+
+        let response = aggregate(&state, "interval=1d&functions=count,first,last").await;
+        assert_eq!(
+            response,
+            json!([
+                { "timestamp": "2024-10-01T00:00:00+00:00", "count": 4, "first": 400.0, "last": 700.0 },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DuckDB's ICU extension, which is downloaded on first use"]
+    async fn aligns_intervals_to_the_timezone() {
+        let state = test_state().await;
+
+        // 23:30 UTC is already the next day in Zurich
+        let response = aggregate(
+            &state,
+            "interval=1d&functions=count,first,last&timezone=Europe/Zurich",
+        )
+        .await;
+        assert_eq!(
+            response,
+            json!([
+                { "timestamp": "2024-10-01T00:00:00+02:00", "count": 3, "first": 400.0, "last": 600.0 },
+                { "timestamp": "2024-10-02T00:00:00+02:00", "count": 1, "first": 700.0, "last": 700.0 },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_aggregates_to_the_time_range() {
+        let state = test_state().await;
+
+        let response = aggregate(
+            &state,
+            "interval=1h&functions=count&from=2024-10-01T06:30:00Z&to=2024-10-01T08:00:00Z",
+        )
+        .await;
+        assert_eq!(
+            response,
+            json!([
+                { "timestamp": "2024-10-01T06:00:00+00:00", "count": 1 },
+                { "timestamp": "2024-10-01T07:00:00+00:00", "count": 1 },
+            ])
+        );
+    }
+}
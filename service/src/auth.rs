@@ -1,20 +1,90 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+};
 
-use crate::{error::AppError, AppState};
+use crate::{
+    error::AppError,
+    limits::{enforce_limits, BodyLimit, EmitterLimits},
+    utils::glob_match,
+    AppState,
+};
 use axum::{
     async_trait,
-    extract::{FromRequestParts, Path},
+    extract::{ConnectInfo, FromRequestParts, Path},
     http::{request::Parts, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
-use tracing::{error, info};
+use duckdb::params;
+use jiff::Timestamp;
+use tracing::{error, info, warn};
 
 pub struct AuthenticatedEmitter {
     pub description: String,
+    // glob patterns of the buckets the emitter may write to, any bucket if not set
+    pub allowed_buckets: Option<Vec<String>>,
+    pub limits: EmitterLimits,
+}
+
+impl AuthenticatedEmitter {
+    pub fn may_write(&self, bucket: &str) -> bool {
+        match &self.allowed_buckets {
+            Some(patterns) => patterns.iter().any(|pattern| glob_match(pattern, bucket)),
+            None => true,
+        }
+    }
+
+    /// Reject writes to buckets outside of the emitter's allowlist
+    pub fn authorize_bucket(&self, bucket: &str) -> Result<(), AppError> {
+        if self.may_write(bucket) {
+            Ok(())
+        } else {
+            error!(message = "Emitter not allowed to write to bucket", bucket);
+            Err(AppError::Status(StatusCode::FORBIDDEN))
+        }
+    }
 }
 
 pub struct AuthenticatedUser {}
 
+/// Addresses of reverse proxies whose `X-Forwarded-For` header is trusted,
+/// configured as a comma separated list in `TRUSTED_PROXIES`
+pub fn trusted_proxies_from_env() -> Vec<IpAddr> {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|ip| ip.trim())
+        .filter(|ip| !ip.is_empty())
+        .filter_map(|ip| match ip.parse() {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                warn!(message = "Ignoring trusted proxy", ip, error = %e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Client address. Anyone can send `X-Forwarded-For`, so its first entry is
+/// only used for requests coming from a trusted reverse proxy.
+fn source_ip(parts: &Parts, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> Option<String> {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    let forwarded = headers
+        .get("X-Forwarded-For")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_string());
+
+    match (peer, forwarded) {
+        (Some(peer), Some(forwarded)) if trusted_proxies.contains(&peer) => Some(forwarded),
+        (peer, _) => peer.map(|ip| ip.to_string()),
+    }
+}
+
 #[async_trait]
 impl FromRequestParts<AppState> for AuthenticatedEmitter {
     type Rejection = AppError;
@@ -44,7 +114,17 @@ impl FromRequestParts<AppState> for AuthenticatedEmitter {
                 .unwrap(),
             None => {
                 info!(message = "Missing token header");
-                match path.get("emitter") {
+                // InfluxDB clients send `Authorization: Token <token>`, Prometheus `Bearer <token>`
+                let authorization_token = headers
+                    .get("Authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| {
+                        value
+                            .strip_prefix("Token ")
+                            .or(value.strip_prefix("Bearer "))
+                    });
+
+                match authorization_token.or(path.get("emitter").map(|value| value.as_str())) {
                     Some(value) => value,
                     None => {
                         error!(message = "Missing emitter path");
@@ -56,7 +136,7 @@ impl FromRequestParts<AppState> for AuthenticatedEmitter {
 
         let mut stmt = connection.prepare(
             "
-                SELECT description 
+                SELECT description, CAST(allowed_buckets as Text), requests_per_minute, max_body_bytes, max_points_per_day
                 FROM emitters 
                 WHERE token = ?
             ",
@@ -65,15 +145,52 @@ impl FromRequestParts<AppState> for AuthenticatedEmitter {
         let mut rows = stmt.query([token])?;
 
         let emitter = match rows.next()? {
-            Some(row) => AuthenticatedEmitter {
-                description: row.get(0)?,
-            },
+            Some(row) => {
+                let allowed_buckets: Option<String> = row.get(1)?;
+                AuthenticatedEmitter {
+                    description: row.get(0)?,
+                    allowed_buckets: allowed_buckets
+                        .map(|buckets| serde_json::from_str(&buckets))
+                        .transpose()?,
+                    limits: EmitterLimits {
+                        requests_per_minute: row.get(2)?,
+                        max_body_bytes: row.get(3)?,
+                        max_points_per_day: row.get(4)?,
+                    },
+                }
+            }
             None => {
                 error!(message = "No emittor found for token");
                 return Err(AppError::Status(StatusCode::UNAUTHORIZED));
             }
         };
 
+        connection
+            .prepare_cached(
+                "UPDATE emitters SET last_seen = CAST((?) as TIMESTAMPTZ), last_ip = (?) WHERE description = (?);",
+            )?
+            .execute(params![
+                Timestamp::now().to_string(),
+                source_ip(parts, &headers, &state.trusted_proxies),
+                emitter.description
+            ])?;
+
+        enforce_limits(
+            &connection,
+            &state.rate_limiter,
+            &emitter.description,
+            &emitter.limits,
+            &headers,
+        )?;
+
+        // Routes behind `limit_body` check the body against the limit as it is read
+        if let (Some(max), Some(limit)) = (
+            emitter.limits.max_body_bytes,
+            parts.extensions.get::<BodyLimit>(),
+        ) {
+            limit.set(&emitter.description, max.max(0) as u64);
+        }
+
         return Ok(emitter);
     }
 }
@@ -118,3 +235,60 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
         return Err((StatusCode::UNAUTHORIZED, response_headers).into_response());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+
+    fn request_parts(peer: Option<&str>, forwarded_for: Option<&str>) -> (Parts, HeaderMap) {
+        let mut request = Request::builder();
+        if let Some(peer) = peer {
+            request = request.extension(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+        }
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header("X-Forwarded-For", forwarded_for);
+        }
+        let (parts, _) = request.body(()).unwrap().into_parts();
+        let headers = parts.headers.clone();
+
+        (parts, headers)
+    }
+
+    #[test]
+    fn forwarded_for_is_used_behind_trusted_proxies() {
+        let proxies: Vec<IpAddr> = vec!["10.0.0.2".parse().unwrap()];
+
+        let (parts, headers) = request_parts(Some("10.0.0.2:41000"), Some("203.0.113.7, 10.0.0.1"));
+        assert_eq!(
+            source_ip(&parts, &headers, &proxies),
+            Some("203.0.113.7".into())
+        );
+
+        let (parts, headers) = request_parts(Some("10.0.0.2:41000"), None);
+        assert_eq!(
+            source_ip(&parts, &headers, &proxies),
+            Some("10.0.0.2".into())
+        );
+    }
+
+    #[test]
+    fn forwarded_for_from_other_clients_is_ignored() {
+        let proxies: Vec<IpAddr> = vec!["10.0.0.2".parse().unwrap()];
+
+        let (parts, headers) = request_parts(Some("198.51.100.4:52000"), Some("203.0.113.7"));
+        assert_eq!(
+            source_ip(&parts, &headers, &proxies),
+            Some("198.51.100.4".into())
+        );
+        assert_eq!(
+            source_ip(&parts, &headers, &[]),
+            Some("198.51.100.4".into())
+        );
+
+        // Without connection info there is nothing the header could be checked against
+        let (parts, headers) = request_parts(None, Some("203.0.113.7"));
+        assert_eq!(source_ip(&parts, &headers, &proxies), None);
+    }
+}
@@ -1,4 +1,9 @@
-use axum::{extract::State, Json};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use duckdb::{params_from_iter, ToSql};
+use serde::Deserialize;
 
 use crate::{auth::AuthenticatedUser, error::AppError, AppState};
 
@@ -6,13 +11,71 @@ use crate::{auth::AuthenticatedUser, error::AppError, AppState};
 pub async fn get_distinct_buckets(
     State(state): State<AppState>,
     _: AuthenticatedUser,
+    Query(filter): Query<BucketFilter>,
 ) -> Result<Json<Vec<String>>, AppError> {
     let conn = state.connection.lock().await;
 
-    let mut stmt = conn.prepare("SELECT DISTINCT bucket FROM timeseries;")?;
-    let response: Result<Vec<String>, _> = stmt.query_map([], |row| Ok(row.get(0)?))?.collect();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+    let condition = match &filter.pattern {
+        Some(patterns) => bucket_condition(patterns, &mut values),
+        None => "true".into(),
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT DISTINCT bucket FROM timeseries WHERE {} ORDER BY bucket;",
+        condition
+    ))?;
+    let response: Result<Vec<String>, _> = stmt
+        .query_map(
+            params_from_iter(values.iter().map(|value| value.as_ref())),
+            |row| Ok(row.get(0)?),
+        )?
+        .collect();
 
     let response = response?;
 
     Ok(Json(response))
 }
+
+/// SQL condition matching the bucket against comma separated names or glob
+/// patterns like `temperature-*`. The patterns are pushed to `values`.
+pub fn bucket_condition(patterns: &str, values: &mut Vec<Box<dyn ToSql>>) -> String {
+    let conditions: Vec<&str> = patterns
+        .split(',')
+        .map(|pattern| {
+            let pattern = pattern.trim();
+            values.push(Box::new(pattern.to_string()));
+            // Only patterns use GLOB, plain names are compared exactly
+            if pattern.contains(['*', '?', '[']) {
+                "bucket GLOB (?)"
+            } else {
+                "bucket = (?)"
+            }
+        })
+        .collect();
+
+    format!("({})", conditions.join(" OR "))
+}
+
+#[derive(Deserialize)]
+pub struct BucketFilter {
+    // comma separated bucket names or glob patterns
+    pattern: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_names_match_exactly() {
+        let mut values = Vec::new();
+        let condition = bucket_condition("co2, temperature-*,room-?,floor-[12]", &mut values);
+
+        assert_eq!(
+            condition,
+            "(bucket = (?) OR bucket GLOB (?) OR bucket GLOB (?) OR bucket GLOB (?))"
+        );
+        assert_eq!(values.len(), 4);
+    }
+}
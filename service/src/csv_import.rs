@@ -0,0 +1,428 @@
+use std::str::FromStr;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use jiff::{
+    civil::{Date, DateTime, Time},
+    tz::TimeZone,
+    Timestamp,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use tracing::info;
+
+use crate::{
+    auth::AuthenticatedEmitter,
+    data::DataResponse,
+    error::AppError,
+    ingest::{
+        apply_timestamp_policy, bucket_validator, preview_timestamp_policy, timestamp_policy,
+        validate_payload, InsertOutcome, Point,
+    },
+    transforms::Transforms,
+    AppState,
+};
+
+/// Import a CSV file into a bucket. Every row becomes one data point with the
+/// configured columns as typed payload fields. Rows that fail to parse, fall
+/// outside of the timestamp policy or don't match the bucket's schema are
+/// reported and skipped, the rest is written in one go.
+#[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
+pub async fn import_csv(
+    State(state): State<AppState>,
+    emitter: AuthenticatedEmitter,
+    Path((_, bucket)): Path<(String, String)>,
+    Json(request): Json<CsvImportRequest>,
+) -> Result<Json<CsvImportResponse>, AppError> {
+    emitter.authorize_bucket(&bucket)?;
+
+    let (rows, mut response) = parse_csv(&bucket, &request)?;
+
+    let conn = state.connection.lock().await;
+    let validator = bucket_validator(&conn, &bucket)?;
+    let policy = timestamp_policy(&conn, state.timestamp_policy.as_ref(), &bucket)?;
+    let mut transforms = Transforms::default();
+
+    let mut points = Vec::new();
+    for (row, mut point) in rows {
+        transforms.apply(&conn, &mut point)?;
+        // A dry run must not count timestamp corrections for the emitter
+        let checked = match request.dry_run {
+            Some(_) => preview_timestamp_policy(policy.as_ref(), &mut point),
+            None => {
+                apply_timestamp_policy(&conn, policy.as_ref(), &emitter.description, &mut point)
+            }
+        }
+        .and_then(|_| validate_payload(validator.as_ref(), &point.payload));
+        match checked {
+            Ok(()) => points.push(point),
+            Err(AppError::InputError(reason) | AppError::ValidationError(reason)) => {
+                response.errors.push(CsvRowError { row, reason })
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    drop(conn);
+
+    response.rejected = response.errors.len();
+
+    if let Some(preview) = request.dry_run {
+        response.preview = Some(
+            points
+                .into_iter()
+                .take(preview)
+                .map(|point| DataResponse {
+                    timestamp: point.timestamp,
+                    bucket: point.bucket,
+                    payload: point.payload,
+                })
+                .collect(),
+        );
+        return Ok(Json(response));
+    }
+
+    let outcomes = state
+        .writer
+        .write(Some(&emitter.description), points)
+        .await?;
+    response.imported = outcomes
+        .iter()
+        .filter(|outcome| **outcome == InsertOutcome::Inserted)
+        .count();
+
+    info!(
+        message = "Imported CSV",
+        imported = response.imported,
+        rejected = response.rejected
+    );
+
+    Ok(Json(response))
+}
+
+/// Map the rows of the file to points, paired with their line in the file.
+/// Rows that fail to parse are reported in the response.
+fn parse_csv(
+    bucket: &str,
+    request: &CsvImportRequest,
+) -> Result<(Vec<(u64, Point)>, CsvImportResponse), AppError> {
+    let timezone = match &request.timestamp.timezone {
+        Some(tz) => TimeZone::get(tz).map_err(AppError::DateInputError)?,
+        None => TimeZone::UTC,
+    };
+
+    let mut reader = csv::Reader::from_reader(request.csv.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| AppError::InputError(e.to_string()))?
+        .clone();
+    let column_index = |column: &str| {
+        headers
+            .iter()
+            .position(|header| header.trim() == column)
+            .ok_or(AppError::InputError(format!("Unknown column {}", column)))
+    };
+
+    let timestamp_index = column_index(&request.timestamp.column)?;
+    let fields = request
+        .fields
+        .iter()
+        .map(|field| Ok((column_index(&field.column)?, field)))
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    let mut response = CsvImportResponse::default();
+    let mut rows = Vec::new();
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                response.errors.push(CsvRowError {
+                    row: e.position().map(|p| p.line()).unwrap_or_default(),
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let row = record.position().map(|p| p.line()).unwrap_or_default();
+
+        let timestamp = match parse_timestamp(
+            record.get(timestamp_index).unwrap_or_default(),
+            request.timestamp.format.as_deref(),
+            &timezone,
+        ) {
+            Ok(timestamp) => timestamp,
+            Err(reason) => {
+                response.errors.push(CsvRowError { row, reason });
+                continue;
+            }
+        };
+
+        let payload: Result<Map<String, Value>, String> = fields
+            .iter()
+            .filter_map(|(index, field)| {
+                let value = record.get(*index).unwrap_or_default().trim();
+                // Empty cells are left out of the payload
+                if value.is_empty() {
+                    return None;
+                }
+                let name = field.name.clone().unwrap_or(field.column.clone());
+                Some(parse_value(value, field.r#type).map(|value| (name, value)))
+            })
+            .collect();
+
+        match payload {
+            Ok(payload) => rows.push((
+                row,
+                Point {
+                    timestamp: timestamp.to_string(),
+                    bucket: bucket.into(),
+                    payload: Value::Object(payload),
+                    idempotency_key: None,
+                    original_timestamp: None,
+                },
+            )),
+            Err(reason) => response.errors.push(CsvRowError { row, reason }),
+        }
+    }
+
+    Ok((rows, response))
+}
+
+/// Parse RFC 3339 timestamps like `upload_data`, or civil date times in
+/// `format` interpreted in `timezone`
+fn parse_timestamp(
+    value: &str,
+    format: Option<&str>,
+    timezone: &TimeZone,
+) -> Result<Timestamp, String> {
+    let value = value.trim();
+    let Some(format) = format else {
+        return Timestamp::from_str(value).map_err(|e| e.to_string());
+    };
+
+    let datetime = match DateTime::strptime(format, value) {
+        Ok(datetime) => datetime,
+        // Formats without time of day refer to midnight
+        Err(e) => Date::strptime(format, value)
+            .map(|date| date.to_datetime(Time::midnight()))
+            .map_err(|_| e.to_string())?,
+    };
+
+    datetime
+        .to_zoned(timezone.clone())
+        .map(|zoned| zoned.timestamp())
+        .map_err(|e| e.to_string())
+}
+
+fn parse_value(value: &str, field_type: FieldType) -> Result<Value, String> {
+    match field_type {
+        FieldType::Number => value
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number)
+            .ok_or(format!("Invalid number {}", value)),
+        FieldType::Bool => match value.to_lowercase().as_str() {
+            "true" | "yes" | "1" => Ok(Value::Bool(true)),
+            "false" | "no" | "0" => Ok(Value::Bool(false)),
+            _ => Err(format!("Invalid bool {}", value)),
+        },
+        FieldType::String => Ok(Value::String(value.into())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CsvImportRequest {
+    csv: String,
+    timestamp: CsvTimestamp,
+    fields: Vec<CsvField>,
+    // parse the file and return the first `dry_run` rows without writing anything
+    dry_run: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct CsvTimestamp {
+    column: String,
+    // strftime format, RFC 3339 if not set
+    format: Option<String>,
+    // IANA time zone for formats without offset, UTC if not set
+    timezone: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CsvField {
+    column: String,
+    // payload key, defaults to the column name
+    name: Option<String>,
+    r#type: FieldType,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Number,
+    Bool,
+    String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CsvImportResponse {
+    imported: usize,
+    rejected: usize,
+    errors: Vec<CsvRowError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview: Option<Vec<DataResponse>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CsvRowError {
+    // line in the CSV file, the header being line 1
+    row: u64,
+    reason: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(csv: &str, timestamp: Value, fields: Value) -> CsvImportRequest {
+        serde_json::from_value(json!({
+            "csv": csv,
+            "timestamp": timestamp,
+            "fields": fields,
+        }))
+        .unwrap()
+    }
+
+    fn payloads(rows: &[(u64, Point)]) -> Vec<(u64, &str, &Value)> {
+        rows.iter()
+            .map(|(row, point)| (*row, point.timestamp.as_str(), &point.payload))
+            .collect()
+    }
+
+    #[test]
+    fn maps_columns_to_typed_fields() {
+        let request = request(
+            "time,temp,open,room,ignored\n\
+             2024-10-01T06:00:00Z,21.5,yes,office,x\n\
+             2024-10-01T07:00:00+02:00,,0,kitchen,y\n",
+            json!({ "column": "time" }),
+            json!([
+                { "column": "temp", "name": "temperature", "type": "number" },
+                { "column": "open", "type": "bool" },
+                { "column": "room", "type": "string" },
+            ]),
+        );
+
+        let (rows, response) = parse_csv("climate", &request).unwrap();
+        assert!(response.errors.is_empty());
+        assert!(rows.iter().all(|(_, point)| point.bucket == "climate"));
+        assert_eq!(
+            payloads(&rows),
+            [
+                (
+                    2,
+                    "2024-10-01T06:00:00Z",
+                    &json!({ "temperature": 21.5, "open": true, "room": "office" })
+                ),
+                // Empty cells are left out
+                (
+                    3,
+                    "2024-10-01T05:00:00Z",
+                    &json!({ "open": false, "room": "kitchen" })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_columns_fail_the_import() {
+        let request = request(
+            "time,temp\n2024-10-01T06:00:00Z,21.5\n",
+            json!({ "column": "time" }),
+            json!([{ "column": "humidity", "type": "number" }]),
+        );
+
+        let error = parse_csv("climate", &request).err().unwrap();
+        assert!(matches!(error, AppError::InputError(e) if e == "Unknown column humidity"));
+    }
+
+    #[test]
+    fn reports_rows_that_fail_to_parse() {
+        let request = request(
+            "time,temp,open\n\
+             2024-10-01T06:00:00Z,21.5,true\n\
+             yesterday,21.5,true\n\
+             2024-10-01T08:00:00Z,warm,true\n\
+             2024-10-01T09:00:00Z,22,maybe\n\
+             2024-10-01T10:00:00Z\n",
+            json!({ "column": "time" }),
+            json!([
+                { "column": "temp", "type": "number" },
+                { "column": "open", "type": "bool" },
+            ]),
+        );
+
+        let (rows, response) = parse_csv("climate", &request).unwrap();
+        assert_eq!(rows.len(), 1);
+
+        let errors: Vec<(u64, &str)> = response
+            .errors
+            .iter()
+            .map(|e| (e.row, e.reason.as_str()))
+            .collect();
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0].0, 3);
+        assert_eq!(errors[1], (4, "Invalid number warm"));
+        assert_eq!(errors[2], (5, "Invalid bool maybe"));
+        // Rows with a different number of columns are rejected by the CSV reader
+        assert_eq!(errors[3].0, 6);
+    }
+
+    #[test]
+    fn parses_formats_in_timezone() {
+        let zurich = TimeZone::get("Europe/Zurich").unwrap();
+        let parse =
+            |value, format| parse_timestamp(value, format, &zurich).map(|ts| ts.to_string());
+
+        assert_eq!(
+            parse("01.10.2024 08:00", Some("%d.%m.%Y %H:%M")),
+            Ok("2024-10-01T06:00:00Z".into())
+        );
+        // Winter time
+        assert_eq!(
+            parse("2024-12-01 08:00:00", Some("%Y-%m-%d %H:%M:%S")),
+            Ok("2024-12-01T07:00:00Z".into())
+        );
+        // Dates refer to midnight
+        assert_eq!(
+            parse("2024-10-01", Some("%Y-%m-%d")),
+            Ok("2024-09-30T22:00:00Z".into())
+        );
+        // Without format, RFC 3339 timestamps carry their own offset
+        assert_eq!(
+            parse(" 2024-10-01T08:00:00+02:00 ", None),
+            Ok("2024-10-01T06:00:00Z".into())
+        );
+        assert!(parse("2024-10-01 08:00", None).is_err());
+        assert!(parse("32.10.2024 08:00", Some("%d.%m.%Y %H:%M")).is_err());
+    }
+
+    #[test]
+    fn unknown_timezones_fail_the_import() {
+        let request = request(
+            "time\n2024-10-01\n",
+            json!({ "column": "time", "format": "%Y-%m-%d", "timezone": "Mars/Olympus" }),
+            json!([]),
+        );
+
+        assert!(matches!(
+            parse_csv("climate", &request),
+            Err(AppError::DateInputError(_))
+        ));
+    }
+}
@@ -1,29 +1,54 @@
-use std::{collections::HashMap, str::FromStr, u32};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    u32,
+};
 
 use axum::{
+    body::Body,
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use duckdb::params;
+use duckdb::{params, params_from_iter, ToSql};
+use futures_util::StreamExt;
 use jiff::{Span, Timestamp, Zoned};
+use jsonschema::Validator;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tracing::info;
+use serde_json::{Map, Number, Value};
+use tracing::{error, info};
 
 use crate::{
     auth::{AuthenticatedEmitter, AuthenticatedUser},
+    buckets::bucket_condition,
     error::AppError,
-    utils::sample,
+    ingest::{
+        apply_timestamp_policy, bucket_schema, bucket_validator, compile_schema,
+        derive_idempotency_key, idempotency_key, timestamp_policy, validate_payload, InsertOutcome,
+        Point, TimestampPolicy,
+    },
+    payload_filters::parse_filters,
+    transforms::Transforms,
+    utils::{sample, SampleMethod},
     AppState,
 };
 
+// Number of NDJSON records buffered before they are appended to the DB
+const NDJSON_CHUNK_SIZE: usize = 1000;
+
+// Query parameters of URL-only uploads that are not part of the payload
+const RESERVED_URL_PARAMS: [&str; 2] = ["timestamp", "idempotency_key"];
+
+/// Points in a time range, newest first unless `order=asc`. With `page_size`
+/// set the points are returned in pages, each with a cursor continuing after
+/// its last point.
 #[tracing::instrument(skip_all)]
 pub async fn get_data(
     State(state): State<AppState>,
     _: AuthenticatedUser,
     Query(filters): Query<DataFilter>,
-) -> Result<(StatusCode, Json<Vec<DataResponse>>), AppError> {
+) -> Result<Response, AppError> {
     let mut from = if let Some(f) = filters.from {
         Timestamp::from_str(&f)
             .map_err(|e| AppError::DateInputError(e))?
@@ -48,49 +73,244 @@ pub async fn get_data(
             .to_string();
     }
 
-    let limit = filters.limit.unwrap_or(u32::MAX);
+    let sample_method = filters.sample_method.unwrap_or_default();
+    if !matches!(sample_method, SampleMethod::Stride) && filters.sample_field.is_none() {
+        return Err(AppError::InputError(format!(
+            "sample_method {:?} requires sample_field",
+            sample_method
+        )));
+    }
+
+    let order = filters.order.unwrap_or_default();
+    let cursor = filters
+        .cursor
+        .as_deref()
+        .map(|cursor| Cursor::decode(cursor, order))
+        .transpose()?;
+    if filters.page_size.is_some() && filters.sample.is_some() {
+        return Err(AppError::InputError(
+            "sample can't be combined with page_size".into(),
+        ));
+    }
+    if cursor.is_some() && filters.page_size.is_none() {
+        return Err(AppError::InputError("cursor requires page_size".into()));
+    }
+    let group_by_bucket = filters.group_by_bucket.unwrap_or(false);
+    if group_by_bucket && filters.page_size.is_some() {
+        return Err(AppError::InputError(
+            "group_by_bucket can't be combined with page_size".into(),
+        ));
+    }
 
+    // One more row than requested tells whether there is a next page
+    let limit = match filters.page_size {
+        Some(page_size) => page_size.saturating_add(1),
+        None => filters.limit.unwrap_or(u32::MAX),
+    };
+
+    // Query parameters aren't `Send`, so they are only collected once the lock is held
     let conn = state.connection.lock().await;
-    let mut stmt;
 
-    let response: Result<Vec<DataResponse>, _> = if let Some(bucket) = filters.bucket {
-        stmt = conn
-        .prepare(
-            "SELECT cast(timestamp as Text), payload, bucket FROM timeseries WHERE bucket = (?) AND timestamp > CAST((?) as TIMESTAMP) AND timestamp < CAST((?) AS TIMESTAMP) ORDER BY timestamp DESC LIMIT (?);",
-        )?;
-        stmt.query_map(params![bucket, from, to, limit], |row| {
-            let payload: String = row.get(1)?;
-            Ok(DataResponse {
-                timestamp: row.get(0)?,
-                payload: serde_json::from_str(&payload).unwrap(),
-                bucket: row.get(2)?,
-            })
-        })?
-        .collect()
+    // Projected fields are picked from the payload by DuckDB and keyed by their path
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+    let payload_column = match &filters.fields {
+        Some(fields) => {
+            let mut arguments = Vec::new();
+            for path in fields.split(',').map(str::trim) {
+                if !path.starts_with('$') {
+                    return Err(AppError::InputError(format!(
+                        "Expected JSON path like $.co2, found '{}'",
+                        path
+                    )));
+                }
+                let key = path.strip_prefix("$.").unwrap_or(path);
+                values.push(Box::new(key.to_string()));
+                values.push(Box::new(path.to_string()));
+                arguments.push("(?), json_extract(payload, (?))");
+            }
+            format!("json_object({})", arguments.join(", "))
+        }
+        None => "payload".to_string(),
+    };
+
+    let mut conditions: Vec<String> = vec![
+        "timestamp > CAST((?) as TIMESTAMP)".into(),
+        "timestamp < CAST((?) as TIMESTAMP)".into(),
+    ];
+    values.push(Box::new(from));
+    values.push(Box::new(to));
+    if let Some(bucket) = &filters.bucket {
+        conditions.push(bucket_condition(bucket, &mut values));
+    }
+    if let Some(filter) = &filters.filter {
+        for filter in parse_filters(filter)? {
+            conditions.push(filter.sql(&mut values)?.into());
+        }
+    }
+    if let Some(cursor) = &cursor {
+        // Points sharing the cursor's timestamp are told apart by their row
+        let condition = match order {
+            SortOrder::Asc => {
+                "(epoch_us(timestamp) > (?) OR (epoch_us(timestamp) = (?) AND rowid > (?)))"
+            }
+            SortOrder::Desc => {
+                "(epoch_us(timestamp) < (?) OR (epoch_us(timestamp) = (?) AND rowid < (?)))"
+            }
+        };
+        conditions.push(condition.into());
+        values.push(Box::new(cursor.timestamp));
+        values.push(Box::new(cursor.timestamp));
+        values.push(Box::new(cursor.row));
+    }
+    values.push(Box::new(limit));
+
+    // Grouped responses hold up to `limit` points per bucket
+    let query = if group_by_bucket {
+        format!(
+            "SELECT cast(timestamp as Text), CAST({} as Text), bucket, epoch_us(timestamp), rowid FROM timeseries WHERE {} QUALIFY row_number() OVER (PARTITION BY bucket ORDER BY timestamp {}, rowid {}) <= (?) ORDER BY bucket, timestamp {}, rowid {};",
+            payload_column,
+            conditions.join(" AND "),
+            order.sql(),
+            order.sql(),
+            order.sql(),
+            order.sql()
+        )
     } else {
-        stmt = conn
-        .prepare(
-            "SELECT cast(timestamp as Text), payload, bucket FROM timeseries WHERE timestamp > CAST((?) as TIMESTAMP) AND timestamp < CAST((?) as TIMESTAMP) ORDER BY timestamp DESC LIMIT (?);",
-        )?;
-        stmt.query_map(params![from, to, limit], |row| {
-            let payload: String = row.get(1)?;
-            Ok(DataResponse {
-                timestamp: row.get(0)?,
-                payload: serde_json::from_str(&payload).unwrap(),
-                bucket: row.get(2)?,
-            })
-        })?
-        .collect()
+        format!(
+            "SELECT cast(timestamp as Text), CAST({} as Text), bucket, epoch_us(timestamp), rowid FROM timeseries WHERE {} ORDER BY timestamp {}, rowid {} LIMIT (?);",
+            payload_column,
+            conditions.join(" AND "),
+            order.sql(),
+            order.sql()
+        )
     };
 
+    let mut stmt = conn.prepare(&query)?;
+
+    let response: Result<Vec<(DataResponse, Cursor)>, _> = stmt
+        .query_map(
+            params_from_iter(values.iter().map(|value| value.as_ref())),
+            |row| {
+                let payload: String = row.get(1)?;
+                Ok((
+                    DataResponse {
+                        timestamp: row.get(0)?,
+                        payload: serde_json::from_str(&payload).unwrap(),
+                        bucket: row.get(2)?,
+                    },
+                    Cursor {
+                        order,
+                        timestamp: row.get(3)?,
+                        row: row.get(4)?,
+                    },
+                ))
+            },
+        )?
+        .collect();
+
     let mut response = response?;
 
     // Format dates in DB (can't be done in query_map due to error handling)
-    for d in response.iter_mut() {
+    for (d, _) in response.iter_mut() {
         d.timestamp = Timestamp::from_str(&d.timestamp)?.to_string();
     }
 
-    Ok((StatusCode::OK, Json(sample(filters.sample, response))))
+    if group_by_bucket {
+        let mut grouped: BTreeMap<String, Vec<DataResponse>> = BTreeMap::new();
+        for (d, _) in response {
+            grouped.entry(d.bucket.clone()).or_default().push(d);
+        }
+        let grouped: BTreeMap<String, Vec<DataResponse>> = grouped
+            .into_iter()
+            .map(|(bucket, data)| {
+                let data = sample(
+                    filters.sample,
+                    sample_method,
+                    filters.sample_field.as_deref(),
+                    data,
+                );
+                (bucket, data)
+            })
+            .collect();
+
+        return Ok((StatusCode::OK, Json(grouped)).into_response());
+    }
+
+    let Some(page_size) = filters.page_size else {
+        let response = response.into_iter().map(|(d, _)| d).collect();
+        return Ok((
+            StatusCode::OK,
+            Json(sample(
+                filters.sample,
+                sample_method,
+                filters.sample_field.as_deref(),
+                response,
+            )),
+        )
+            .into_response());
+    };
+
+    let next = if response.len() > page_size as usize {
+        response.truncate(page_size as usize);
+        response.last().map(|(_, cursor)| cursor.encode())
+    } else {
+        None
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json(DataPage {
+            data: response.into_iter().map(|(d, _)| d).collect(),
+            next,
+        }),
+    )
+        .into_response())
+}
+
+/// Latest point of every bucket matching the comma separated names or glob
+/// patterns in `bucket`, of all buckets if not set
+#[tracing::instrument(skip_all)]
+pub async fn get_latest_data(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Query(filters): Query<LatestDataFilter>,
+) -> Result<Json<BTreeMap<String, DataResponse>>, AppError> {
+    let conn = state.connection.lock().await;
+
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+    let condition = match &filters.bucket {
+        Some(bucket) => bucket_condition(bucket, &mut values),
+        None => "true".into(),
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT cast(timestamp as Text), payload, bucket FROM timeseries WHERE {} QUALIFY row_number() OVER (PARTITION BY bucket ORDER BY timestamp DESC, rowid DESC) = 1;",
+        condition
+    ))?;
+
+    let response: Result<Vec<DataResponse>, _> = stmt
+        .query_map(
+            params_from_iter(values.iter().map(|value| value.as_ref())),
+            |row| {
+                let payload: String = row.get(1)?;
+                Ok(DataResponse {
+                    timestamp: row.get(0)?,
+                    payload: serde_json::from_str(&payload).unwrap(),
+                    bucket: row.get(2)?,
+                })
+            },
+        )?
+        .collect();
+
+    let response = response?
+        .into_iter()
+        .map(|mut d| {
+            d.timestamp = Timestamp::from_str(&d.timestamp)?.to_string();
+            Ok((d.bucket.clone(), d))
+        })
+        .collect::<Result<BTreeMap<_, _>, AppError>>()?;
+
+    Ok(Json(response))
 }
 
 #[tracing::instrument(skip_all)]
@@ -147,33 +367,337 @@ pub async fn delete_data(
 pub async fn upload_data(
     State(state): State<AppState>,
     emitter: AuthenticatedEmitter,
+    headers: HeaderMap,
     Json(request): Json<Data>,
 ) -> Result<StatusCode, AppError> {
+    emitter.authorize_bucket(&request.bucket)?;
+
     let conn = state.connection.lock().await;
-    let mut stmt =
-        conn.prepare("INSERT INTO timeseries (timestamp, bucket, payload) VALUES (?, ?, ?);")?;
-    let payload = serde_json::to_string(&request.payload)?;
     let timestamp = match request.timestamp {
         Some(ts) => Timestamp::from_str(&ts)
             .map_err(|e| AppError::DateInputError(e))?
             .to_string(),
         None => Timestamp::now().to_string(),
     };
-    stmt.execute(params![timestamp, request.bucket, payload])?;
+    let mut point = Point {
+        timestamp,
+        bucket: request.bucket,
+        payload: request.payload,
+        idempotency_key: request.idempotency_key.or(idempotency_key(&headers)),
+        original_timestamp: None,
+    };
+
+    let policy = timestamp_policy(&conn, state.timestamp_policy.as_ref(), &point.bucket)?;
+    apply_timestamp_policy(&conn, policy.as_ref(), &emitter.description, &mut point)?;
+
+    Transforms::default().apply(&conn, &mut point)?;
+    let validator = bucket_validator(&conn, &point.bucket)?;
+    validate_payload(validator.as_ref(), &point.payload)?;
+    drop(conn);
+
+    // Retries of an already stored point succeed without writing again
+    if state
+        .writer
+        .write(Some(&emitter.description), vec![point])
+        .await?[0]
+        == InsertOutcome::Duplicate
+    {
+        info!(message = "Skipped duplicate data point");
+    }
 
     Ok(StatusCode::OK)
 }
 
+#[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
+pub async fn upload_data_batch(
+    State(state): State<AppState>,
+    emitter: AuthenticatedEmitter,
+    headers: HeaderMap,
+    Json(request): Json<Vec<Value>>,
+) -> Result<Json<BatchUploadResponse>, AppError> {
+    let batch_key = idempotency_key(&headers);
+    let conn = state.connection.lock().await;
+
+    let mut points = Vec::new();
+    let mut rejected = Vec::new();
+    let mut validators: HashMap<String, Option<Validator>> = HashMap::new();
+    let mut policies: HashMap<String, Option<TimestampPolicy>> = HashMap::new();
+    let mut transforms = Transforms::default();
+    for (index, item) in request.into_iter().enumerate() {
+        // Items are deserialized one by one so a malformed item doesn't reject the whole batch
+        let data: Data = match serde_json::from_value(item) {
+            Ok(data) => data,
+            Err(e) => {
+                rejected.push(BatchRejection {
+                    index,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let timestamp = match data.timestamp {
+            Some(ts) => match Timestamp::from_str(&ts) {
+                Ok(ts) => ts.to_string(),
+                Err(e) => {
+                    rejected.push(BatchRejection {
+                        index,
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            },
+            None => Timestamp::now().to_string(),
+        };
+        let mut point = Point {
+            timestamp,
+            bucket: data.bucket,
+            payload: data.payload,
+            idempotency_key: data
+                .idempotency_key
+                .or(derive_idempotency_key(&batch_key, index)),
+            original_timestamp: None,
+        };
+
+        if !emitter.may_write(&point.bucket) {
+            rejected.push(BatchRejection {
+                index,
+                reason: format!("Emitter may not write to bucket {}", point.bucket),
+            });
+            continue;
+        }
+
+        if !policies.contains_key(&point.bucket) {
+            let policy = timestamp_policy(&conn, state.timestamp_policy.as_ref(), &point.bucket)?;
+            policies.insert(point.bucket.clone(), policy);
+        }
+        let policy = policies[&point.bucket].as_ref();
+        if let Err(e) = apply_timestamp_policy(&conn, policy, &emitter.description, &mut point) {
+            rejected.push(BatchRejection {
+                index,
+                reason: e.to_string(),
+            });
+            continue;
+        }
+
+        transforms.apply(&conn, &mut point)?;
+        if !validators.contains_key(&point.bucket) {
+            let validator = bucket_validator(&conn, &point.bucket)?;
+            validators.insert(point.bucket.clone(), validator);
+        }
+        if let Err(e) = validate_payload(validators[&point.bucket].as_ref(), &point.payload) {
+            rejected.push(BatchRejection {
+                index,
+                reason: e.to_string(),
+            });
+            continue;
+        }
+
+        points.push(point);
+    }
+    drop(conn);
+
+    // The writer stores all accepted points at once. Duplicates count as
+    // accepted so a retried batch reports the original result.
+    let outcomes = state
+        .writer
+        .write(Some(&emitter.description), points)
+        .await?;
+    let accepted = outcomes.len();
+    let duplicates = outcomes
+        .iter()
+        .filter(|outcome| **outcome == InsertOutcome::Duplicate)
+        .count();
+
+    info!(
+        message = "Uploaded batch",
+        accepted,
+        duplicates,
+        rejected = rejected.len()
+    );
+
+    Ok(Json(BatchUploadResponse {
+        accepted,
+        duplicates,
+        rejected: rejected.len(),
+        rejections: rejected,
+    }))
+}
+
+#[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
+pub async fn upload_data_ndjson(
+    State(state): State<AppState>,
+    emitter: AuthenticatedEmitter,
+    body: Body,
+) -> Result<Json<NdjsonUploadResponse>, AppError> {
+    let mut stream = body.into_data_stream();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut rows = Vec::with_capacity(NDJSON_CHUNK_SIZE);
+    let mut response = NdjsonUploadResponse::default();
+    let mut checks = NdjsonChecks::default();
+
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes.map_err(|e| {
+            error!(message = "Failed to read request body", error = %e);
+            AppError::Status(StatusCode::BAD_REQUEST)
+        })?;
+        buffer.extend_from_slice(&bytes);
+
+        while let Some(position) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=position).collect();
+            handle_ndjson_line(&line, &emitter, &mut rows, &mut response);
+
+            if rows.len() >= NDJSON_CHUNK_SIZE {
+                append_rows(&state, &emitter, &mut checks, &mut rows, &mut response).await?;
+                info!(
+                    message = "NDJSON upload progress",
+                    lines = response.lines,
+                    accepted = response.accepted
+                );
+            }
+        }
+    }
+
+    // Last line might not be terminated by a newline
+    if !buffer.is_empty() {
+        handle_ndjson_line(&buffer, &emitter, &mut rows, &mut response);
+    }
+
+    append_rows(&state, &emitter, &mut checks, &mut rows, &mut response).await?;
+    // Lines failing checks are only reported when their chunk is written
+    response.errors.sort_by_key(|error| error.line);
+    response.rejected = response.errors.len();
+
+    info!(
+        message = "Uploaded NDJSON",
+        lines = response.lines,
+        accepted = response.accepted,
+        rejected = response.rejected
+    );
+
+    Ok(Json(response))
+}
+
+fn handle_ndjson_line(
+    line: &[u8],
+    emitter: &AuthenticatedEmitter,
+    rows: &mut Vec<(usize, Point)>,
+    response: &mut NdjsonUploadResponse,
+) {
+    response.lines += 1;
+
+    let line = String::from_utf8_lossy(line);
+    if line.trim().is_empty() {
+        return;
+    }
+
+    match parse_ndjson_line(&line) {
+        Ok(point) if !emitter.may_write(&point.bucket) => response.errors.push(NdjsonLineError {
+            line: response.lines,
+            reason: format!("Emitter may not write to bucket {}", point.bucket),
+        }),
+        Ok(point) => rows.push((response.lines, point)),
+        Err(reason) => response.errors.push(NdjsonLineError {
+            line: response.lines,
+            reason,
+        }),
+    }
+}
+
+fn parse_ndjson_line(line: &str) -> Result<Point, String> {
+    let data: Data = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let timestamp = match data.timestamp {
+        Some(ts) => Timestamp::from_str(&ts)
+            .map_err(|e| e.to_string())?
+            .to_string(),
+        None => Timestamp::now().to_string(),
+    };
+
+    Ok(Point {
+        timestamp,
+        bucket: data.bucket,
+        payload: data.payload,
+        idempotency_key: data.idempotency_key,
+        original_timestamp: None,
+    })
+}
+
+/// Policies, transforms and validators of the buckets an NDJSON upload writes
+/// to, looked up once per upload
+#[derive(Default)]
+struct NdjsonChecks {
+    policies: HashMap<String, Option<TimestampPolicy>>,
+    transforms: Transforms,
+    validators: HashMap<String, Option<Validator>>,
+}
+
+/// Check buffered points like the batch endpoint does, hand the passing ones
+/// to the writer and clear the buffer. Failing points are reported as line
+/// errors.
+async fn append_rows(
+    state: &AppState,
+    emitter: &AuthenticatedEmitter,
+    checks: &mut NdjsonChecks,
+    rows: &mut Vec<(usize, Point)>,
+    response: &mut NdjsonUploadResponse,
+) -> Result<(), AppError> {
+    let mut points = Vec::with_capacity(rows.len());
+    {
+        let conn = state.connection.lock().await;
+        for (line, mut point) in rows.drain(..) {
+            if !checks.policies.contains_key(&point.bucket) {
+                let policy =
+                    timestamp_policy(&conn, state.timestamp_policy.as_ref(), &point.bucket)?;
+                checks.policies.insert(point.bucket.clone(), policy);
+            }
+            let policy = checks.policies[&point.bucket].as_ref();
+            if let Err(e) = apply_timestamp_policy(&conn, policy, &emitter.description, &mut point)
+            {
+                response.errors.push(NdjsonLineError {
+                    line,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+
+            checks.transforms.apply(&conn, &mut point)?;
+            if !checks.validators.contains_key(&point.bucket) {
+                let validator = bucket_validator(&conn, &point.bucket)?;
+                checks.validators.insert(point.bucket.clone(), validator);
+            }
+            let validator = checks.validators[&point.bucket].as_ref();
+            if let Err(e) = validate_payload(validator, &point.payload) {
+                response.errors.push(NdjsonLineError {
+                    line,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+
+            points.push(point);
+        }
+    }
+
+    let outcomes = state
+        .writer
+        .write(Some(&emitter.description), points)
+        .await?;
+    response.accepted += outcomes.len();
+
+    Ok(())
+}
+
 #[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
 pub async fn upload_data_url_only(
     State(state): State<AppState>,
     emitter: AuthenticatedEmitter,
+    headers: HeaderMap,
     Path((_, bucket)): Path<(String, String)>,
     Query(data): Query<HashMap<String, String>>,
 ) -> Result<StatusCode, AppError> {
+    emitter.authorize_bucket(&bucket)?;
+
     let conn = state.connection.lock().await;
-    let mut stmt =
-        conn.prepare("INSERT INTO timeseries (timestamp, bucket, payload) VALUES (?, ?, ?);")?;
 
     let timestamp = match data.get("timestamp") {
         Some(ts) => Timestamp::from_str(&ts)
@@ -181,12 +705,120 @@ pub async fn upload_data_url_only(
             .to_string(),
         None => Timestamp::now().to_string(),
     };
-    let payload = serde_json::to_string(&data)?;
-    stmt.execute(params![timestamp, bucket, payload])?;
+
+    // Types declared in the bucket's schema take precedence over inferred ones
+    let schema = bucket_schema(&conn, &bucket)?;
+    let mut point = Point {
+        timestamp,
+        payload: url_payload(&data, schema.as_ref())?,
+        bucket,
+        idempotency_key: data
+            .get("idempotency_key")
+            .cloned()
+            .or(idempotency_key(&headers)),
+        original_timestamp: None,
+    };
+
+    let policy = timestamp_policy(&conn, state.timestamp_policy.as_ref(), &point.bucket)?;
+    apply_timestamp_policy(&conn, policy.as_ref(), &emitter.description, &mut point)?;
+
+    Transforms::default().apply(&conn, &mut point)?;
+    let validator = schema
+        .map(|schema| compile_schema(&point.bucket, &schema))
+        .transpose()?;
+    validate_payload(validator.as_ref(), &point.payload)?;
+    drop(conn);
+
+    if state
+        .writer
+        .write(Some(&emitter.description), vec![point])
+        .await?[0]
+        == InsertOutcome::Duplicate
+    {
+        info!(message = "Skipped duplicate data point");
+    }
 
     Ok(StatusCode::OK)
 }
 
+/// Build a JSON payload from query parameters. Reserved parameters are left
+/// out, dotted keys become nested objects and values are typed as declared in
+/// `schema` or inferred otherwise.
+fn url_payload(
+    params: &HashMap<String, String>,
+    schema: Option<&Value>,
+) -> Result<Value, AppError> {
+    // Sorted so conflicting keys are reported deterministically
+    let mut keys: Vec<&String> = params
+        .keys()
+        .filter(|key| !RESERVED_URL_PARAMS.contains(&key.as_str()))
+        .collect();
+    keys.sort();
+
+    let mut payload = Map::new();
+    for key in keys {
+        let path: Vec<&str> = key.split('.').collect();
+        let declared = schema.and_then(|schema| declared_type(schema, &path));
+        let value = infer_value(&params[key], declared);
+
+        let (last, parents) = path.split_last().unwrap_or((&"", &[]));
+        let mut object = &mut payload;
+        for parent in parents {
+            let entry = object
+                .entry(parent.to_string())
+                .or_insert(Value::Object(Map::new()));
+            object = entry.as_object_mut().ok_or(AppError::InputError(format!(
+                "Conflicting parameter {}",
+                key
+            )))?;
+        }
+        if object.contains_key(*last) {
+            return Err(AppError::InputError(format!(
+                "Conflicting parameter {}",
+                key
+            )));
+        }
+        object.insert(last.to_string(), value);
+    }
+
+    Ok(Value::Object(payload))
+}
+
+/// Type of the property at `path` as declared in a JSON Schema
+fn declared_type<'a>(schema: &'a Value, path: &[&str]) -> Option<&'a str> {
+    path.iter()
+        .try_fold(schema, |node, key| node.get("properties")?.get(key))?
+        .get("type")?
+        .as_str()
+}
+
+fn infer_value(raw: &str, declared: Option<&str>) -> Value {
+    let number = || {
+        raw.parse::<i64>().ok().map(Value::from).or(raw
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number))
+    };
+
+    let value = match declared {
+        Some("string") => None,
+        Some("number") | Some("integer") => number(),
+        Some("boolean") => raw.parse::<bool>().ok().map(Value::Bool),
+        Some("null") => (raw == "null").then_some(Value::Null),
+        _ => match raw {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            "null" => Some(Value::Null),
+            // Keep values like zip codes with leading zeros as strings
+            _ if raw.len() > 1 && raw.starts_with('0') && !raw.starts_with("0.") => None,
+            _ => number(),
+        },
+    };
+
+    value.unwrap_or(Value::String(raw.into()))
+}
+
 #[derive(Deserialize)]
 pub struct DataFilter {
     from: Option<String>,
@@ -195,9 +827,103 @@ pub struct DataFilter {
     past_days: Option<u32>,
     // return only last `limit` datapoints
     limit: Option<u32>,
-    // sample at most ~`sample` datapoints from all otherwise returned
+    // sample `sample` datapoints from all otherwise returned
     sample: Option<u32>,
-    // filter down datapoints to ones in `bucket`
+    // `stride` if not set
+    sample_method: Option<SampleMethod>,
+    // dotted path of the numeric payload field `lttb` and `minmax` sample on
+    sample_field: Option<String>,
+    // filter down datapoints to ones in `bucket`, comma separated names or
+    // glob patterns like `temperature-*`
+    bucket: Option<String>,
+    // return datapoints keyed by bucket, `limit` applies per bucket
+    group_by_bucket: Option<bool>,
+    // `desc` if not set
+    order: Option<SortOrder>,
+    // return pages of `page_size` datapoints instead of a plain list
+    page_size: Option<u32>,
+    // `next` of the previous page
+    cursor: Option<String>,
+    // comma separated JSON paths, e.g. `$.co2,$.temperature`, the payload
+    // only holds these fields keyed by their path without `$.`
+    fields: Option<String>,
+    // conditions on payload fields joined by `and`, e.g. `$.co2 > 1000`
+    filter: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    fn sql(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Position after the last point of a page. Encoded as opaque hex string so
+/// clients don't rely on its contents.
+struct Cursor {
+    order: SortOrder,
+    // microseconds since epoch
+    timestamp: i64,
+    // DuckDB `rowid`, separates points with the same timestamp
+    row: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        hex::encode(format!(
+            "{}:{}:{}",
+            self.order.sql(),
+            self.timestamp,
+            self.row
+        ))
+    }
+
+    fn decode(cursor: &str, order: SortOrder) -> Result<Self, AppError> {
+        let invalid = || AppError::InputError(format!("Invalid cursor {}", cursor));
+
+        let decoded = hex::decode(cursor).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let mut parts = decoded.split(':');
+        let (Some(cursor_order), Some(timestamp), Some(row), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+
+        if cursor_order != order.sql() {
+            return Err(AppError::InputError(
+                "Cursor was created for a different order".into(),
+            ));
+        }
+
+        Ok(Cursor {
+            order,
+            timestamp: timestamp.parse().map_err(|_| invalid())?,
+            row: row.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DataPage {
+    data: Vec<DataResponse>,
+    // cursor of the next page, not set on the last one
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LatestDataFilter {
+    // comma separated bucket names or glob patterns
     bucket: Option<String>,
 }
 
@@ -214,16 +940,495 @@ pub struct Data {
     timestamp: Option<String>,
     bucket: String,
     payload: Value,
+    // client supplied key, retries with the same key in the same bucket are ignored
+    idempotency_key: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct DataResponse {
-    timestamp: String,
-    bucket: String,
-    payload: Value,
+    pub(crate) timestamp: String,
+    pub(crate) bucket: String,
+    pub(crate) payload: Value,
 }
 
 #[derive(Debug, Serialize)]
 pub struct DataDeleteResponse {
     affected_rows: usize,
 }
+
+#[derive(Debug, Default, Serialize)]
+pub struct NdjsonUploadResponse {
+    // number of lines read from the body, including blank ones
+    lines: usize,
+    accepted: usize,
+    rejected: usize,
+    errors: Vec<NdjsonLineError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NdjsonLineError {
+    // 1-based line number in the request body
+    line: usize,
+    reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchUploadResponse {
+    accepted: usize,
+    // accepted items that were already stored
+    duplicates: usize,
+    rejected: usize,
+    rejections: Vec<BatchRejection>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchRejection {
+    // position of the rejected item in the request array
+    index: usize,
+    reason: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Uri;
+    use serde_json::json;
+
+    use super::*;
+
+    async fn test_state(points: &[(&str, &str, Value)]) -> AppState {
+        let state = AppState::in_memory().await;
+        for (timestamp, bucket, payload) in points {
+            state
+                .connection
+                .lock()
+                .await
+                .execute(
+                    "INSERT INTO timeseries (timestamp, bucket, payload) VALUES (?, ?, ?);",
+                    params![timestamp, bucket, payload.to_string()],
+                )
+                .unwrap();
+        }
+
+        state
+    }
+
+    async fn query_data(state: &AppState, query: &str) -> Value {
+        let uri: Uri = format!("/api/data?{}", query).parse().unwrap();
+        let filters = Query::try_from_uri(&uri).unwrap();
+        let response = get_data(State(state.clone()), AuthenticatedUser {}, filters)
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Follow `next` from the first page to the last, returning the `n` of
+    /// every point and the number of pages
+    async fn all_pages(state: &AppState, query: &str) -> (Vec<i64>, usize) {
+        let mut numbers = Vec::new();
+        let mut pages = 0;
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = match &cursor {
+                Some(cursor) => query_data(state, &format!("{}&cursor={}", query, cursor)).await,
+                None => query_data(state, query).await,
+            };
+            pages += 1;
+            assert!(pages <= 10, "pagination doesn't terminate");
+
+            for point in page["data"].as_array().unwrap() {
+                numbers.push(point["payload"]["n"].as_i64().unwrap());
+            }
+            match page["next"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => return (numbers, pages),
+            }
+        }
+    }
+
+    async fn query_latest(state: &AppState, query: &str) -> Value {
+        let uri: Uri = format!("/api/data/latest?{}", query).parse().unwrap();
+        let filters = Query::try_from_uri(&uri).unwrap();
+        let Json(latest) = get_latest_data(State(state.clone()), AuthenticatedUser {}, filters)
+            .await
+            .unwrap();
+
+        serde_json::to_value(latest).unwrap()
+    }
+
+    fn bucket_points() -> Vec<(&'static str, &'static str, Value)> {
+        vec![
+            ("2024-10-01T06:00:00Z", "co2", json!({ "n": 0 })),
+            ("2024-10-01T07:00:00Z", "co2", json!({ "n": 1 })),
+            ("2024-10-01T06:00:00Z", "temperature-in", json!({ "n": 2 })),
+            ("2024-10-01T08:00:00Z", "temperature-in", json!({ "n": 3 })),
+            ("2024-10-01T08:00:00Z", "temperature-in", json!({ "n": 4 })),
+            ("2024-10-01T06:00:00Z", "temperature-out", json!({ "n": 5 })),
+            ("2024-10-01T09:00:00Z", "co2-office", json!({ "n": 6 })),
+        ]
+    }
+
+    #[tokio::test]
+    async fn groups_points_of_matching_buckets() {
+        let state = test_state(&bucket_points()).await;
+
+        let grouped = query_data(&state, "bucket=co2,temperature-*&group_by_bucket=true").await;
+        assert_eq!(
+            grouped,
+            json!({
+                "co2": [
+                    { "timestamp": "2024-10-01T07:00:00Z", "bucket": "co2", "payload": { "n": 1 } },
+                    { "timestamp": "2024-10-01T06:00:00Z", "bucket": "co2", "payload": { "n": 0 } },
+                ],
+                "temperature-in": [
+                    { "timestamp": "2024-10-01T08:00:00Z", "bucket": "temperature-in", "payload": { "n": 4 } },
+                    { "timestamp": "2024-10-01T08:00:00Z", "bucket": "temperature-in", "payload": { "n": 3 } },
+                    { "timestamp": "2024-10-01T06:00:00Z", "bucket": "temperature-in", "payload": { "n": 2 } },
+                ],
+                "temperature-out": [
+                    { "timestamp": "2024-10-01T06:00:00Z", "bucket": "temperature-out", "payload": { "n": 5 } },
+                ],
+            })
+        );
+
+        // `limit` applies per bucket
+        let grouped = query_data(
+            &state,
+            "bucket=co2,temperature-*&group_by_bucket=true&limit=1",
+        )
+        .await;
+        let counts: Vec<usize> = grouped
+            .as_object()
+            .unwrap()
+            .values()
+            .map(|points| points.as_array().unwrap().len())
+            .collect();
+        assert_eq!(counts, [1, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn latest_point_of_every_matching_bucket() {
+        let state = test_state(&bucket_points()).await;
+
+        let latest = query_latest(&state, "").await;
+        let numbers: Vec<(&str, i64)> = latest
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(bucket, point)| (bucket.as_str(), point["payload"]["n"].as_i64().unwrap()))
+            .collect();
+        // Of points with the same timestamp the last written one wins
+        assert_eq!(
+            numbers,
+            [
+                ("co2", 1),
+                ("co2-office", 6),
+                ("temperature-in", 4),
+                ("temperature-out", 5)
+            ]
+        );
+
+        // Plain names match exactly, patterns by glob
+        let latest = query_latest(&state, "bucket=co2").await;
+        assert_eq!(
+            latest,
+            json!({ "co2": { "timestamp": "2024-10-01T07:00:00Z", "bucket": "co2", "payload": { "n": 1 } } })
+        );
+        let latest = query_latest(&state, "bucket=co2*,temperature-o?t").await;
+        let buckets: Vec<&String> = latest.as_object().unwrap().keys().collect();
+        assert_eq!(buckets, ["co2", "co2-office", "temperature-out"]);
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = Cursor {
+            order: SortOrder::Asc,
+            timestamp: 1_727_762_400_000_000,
+            row: 7,
+        };
+        let decoded = Cursor::decode(&cursor.encode(), SortOrder::Asc).unwrap();
+        assert_eq!(decoded.order, SortOrder::Asc);
+        assert_eq!(decoded.timestamp, cursor.timestamp);
+        assert_eq!(decoded.row, 7);
+
+        // Cursors only continue pages of the order they were created for
+        assert!(Cursor::decode(&cursor.encode(), SortOrder::Desc).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        for cursor in [
+            "".to_string(),
+            "not hex".to_string(),
+            hex::encode([0xff, 0xfe]),
+            hex::encode("ASC:1"),
+            hex::encode("ASC:1:2:3"),
+            hex::encode("ASC:now:2"),
+            hex::encode("ASC:1:first"),
+        ] {
+            assert!(
+                Cursor::decode(&cursor, SortOrder::Asc).is_err(),
+                "{}",
+                cursor
+            );
+        }
+    }
+
+    /// `filter` query parameter, every byte percent-encoded
+    fn filter_param(filter: &str) -> String {
+        let encoded: String = filter.bytes().map(|b| format!("%{:02X}", b)).collect();
+        format!("filter={}", encoded)
+    }
+
+    /// `n` of the points matching `filter`, in the order they were written
+    async fn filtered(state: &AppState, filter: &str) -> Vec<i64> {
+        let points = query_data(state, &format!("order=asc&{}", filter_param(filter))).await;
+
+        points
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|point| point["payload"]["n"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn filters_points_by_payload_fields() {
+        let state = test_state(&[
+            (
+                "2024-10-01T06:00:00Z",
+                "sensor",
+                json!({ "n": 0, "room": "office", "co2": 1200, "motion": ["walking", "standing"] }),
+            ),
+            (
+                "2024-10-01T06:01:00Z",
+                "sensor",
+                json!({ "n": 1, "room": "kitchen", "co2": 600, "motion": "walking slowly" }),
+            ),
+            (
+                "2024-10-01T06:02:00Z",
+                "sensor",
+                json!({ "n": 2, "room": 42, "co2": "high", "motion": ["walk"] }),
+            ),
+            (
+                "2024-10-01T06:03:00Z",
+                "sensor",
+                json!({ "n": 3, "room": "42", "co2": 1000, "motion": "sitting", "ok": true }),
+            ),
+            (
+                "2024-10-01T06:04:00Z",
+                "sensor",
+                json!({ "n": 4, "co2": null, "ok": false }),
+            ),
+        ])
+        .await;
+
+        // Strings only equal strings, never numbers rendered as text
+        assert_eq!(filtered(&state, r#"$.room == "42""#).await, [3]);
+        assert_eq!(filtered(&state, r#"$.room != "office""#).await, [1, 3]);
+
+        // Values that aren't numbers never match numeric comparisons
+        assert_eq!(filtered(&state, "$.co2 > 900").await, [0, 3]);
+        assert_eq!(filtered(&state, "$.co2 <= 1000").await, [1, 3]);
+        assert_eq!(
+            filtered(&state, r#"$.co2 <= 1000 and $.room == "kitchen""#).await,
+            [1]
+        );
+
+        // Arrays contain equal elements, strings contain substrings
+        assert_eq!(
+            filtered(&state, r#"$.motion contains "walking""#).await,
+            [0, 1]
+        );
+        assert_eq!(
+            filtered(&state, r#"$.motion contains "walk""#).await,
+            [1, 2]
+        );
+
+        assert_eq!(filtered(&state, "$.ok == true").await, [3]);
+        assert_eq!(filtered(&state, "$.ok != true").await, [4]);
+    }
+
+    #[tokio::test]
+    async fn invalid_filters_are_rejected() {
+        let state = test_state(&[]).await;
+
+        for filter in ["co2 > 900", "$.co2 ~ 900", "$.co2 > 900 or $.co2 < 100"] {
+            let uri: Uri = format!("/api/data?{}", filter_param(filter))
+                .parse()
+                .unwrap();
+            let filters = Query::try_from_uri(&uri).unwrap();
+            let result = get_data(State(state.clone()), AuthenticatedUser {}, filters).await;
+            assert!(matches!(result, Err(AppError::InputError(_))), "{}", filter);
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_split_points_with_equal_timestamps() {
+        let state = test_state(&[
+            ("2024-10-01T06:00:00Z", "co2", json!({ "n": 0 })),
+            ("2024-10-01T06:00:01Z", "co2", json!({ "n": 1 })),
+            ("2024-10-01T06:00:01Z", "co2", json!({ "n": 2 })),
+            ("2024-10-01T06:00:01Z", "co2", json!({ "n": 3 })),
+            ("2024-10-01T06:00:02Z", "co2", json!({ "n": 4 })),
+        ])
+        .await;
+
+        // Page boundaries fall between points of the same timestamp
+        let (numbers, pages) = all_pages(&state, "page_size=2&order=asc").await;
+        assert_eq!(numbers, [0, 1, 2, 3, 4]);
+        assert_eq!(pages, 3);
+
+        let (numbers, pages) = all_pages(&state, "page_size=2&order=desc").await;
+        assert_eq!(numbers, [4, 3, 2, 1, 0]);
+        assert_eq!(pages, 3);
+    }
+
+    #[tokio::test]
+    async fn last_full_page_has_no_next() {
+        let state = test_state(&[
+            ("2024-10-01T06:00:00Z", "co2", json!({ "n": 0 })),
+            ("2024-10-01T06:00:01Z", "co2", json!({ "n": 1 })),
+            ("2024-10-01T06:00:02Z", "co2", json!({ "n": 2 })),
+            ("2024-10-01T06:00:03Z", "co2", json!({ "n": 3 })),
+        ])
+        .await;
+
+        let (numbers, pages) = all_pages(&state, "page_size=2").await;
+        assert_eq!(numbers, [3, 2, 1, 0]);
+        assert_eq!(pages, 2);
+
+        let (numbers, pages) = all_pages(&state, "page_size=10&order=asc").await;
+        assert_eq!(numbers, [0, 1, 2, 3]);
+        assert_eq!(pages, 1);
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn infers_value_types() {
+        assert_eq!(infer_value("612", None), json!(612));
+        assert_eq!(infer_value("-3", None), json!(-3));
+        assert_eq!(infer_value("21.5", None), json!(21.5));
+        assert_eq!(infer_value("0", None), json!(0));
+        assert_eq!(infer_value("0.5", None), json!(0.5));
+        assert_eq!(infer_value("true", None), json!(true));
+        assert_eq!(infer_value("false", None), json!(false));
+        assert_eq!(infer_value("null", None), json!(null));
+        assert_eq!(infer_value("office", None), json!("office"));
+        assert_eq!(infer_value("", None), json!(""));
+        // Leading zeros are kept, e.g. zip codes
+        assert_eq!(infer_value("08001", None), json!("08001"));
+        // Not representable as JSON numbers
+        assert_eq!(infer_value("NaN", None), json!("NaN"));
+        assert_eq!(infer_value("inf", None), json!("inf"));
+    }
+
+    #[test]
+    fn declared_types_take_precedence() {
+        assert_eq!(infer_value("612", Some("string")), json!("612"));
+        assert_eq!(infer_value("08001", Some("integer")), json!(8001));
+        assert_eq!(infer_value("08001", Some("number")), json!(8001));
+        assert_eq!(infer_value("true", Some("string")), json!("true"));
+        assert_eq!(infer_value("null", Some("string")), json!("null"));
+        assert_eq!(infer_value("true", Some("boolean")), json!(true));
+        assert_eq!(infer_value("null", Some("null")), json!(null));
+        // Values that don't parse as the declared type stay strings for the schema to reject
+        assert_eq!(infer_value("yes", Some("boolean")), json!("yes"));
+        assert_eq!(infer_value("many", Some("number")), json!("many"));
+    }
+
+    #[test]
+    fn finds_declared_types_of_nested_properties() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "zip": { "type": "string" },
+                "sensor": {
+                    "type": "object",
+                    "properties": { "co2": { "type": "integer" } }
+                }
+            }
+        });
+
+        assert_eq!(declared_type(&schema, &["zip"]), Some("string"));
+        assert_eq!(declared_type(&schema, &["sensor", "co2"]), Some("integer"));
+        assert_eq!(declared_type(&schema, &["sensor"]), Some("object"));
+        assert_eq!(declared_type(&schema, &["sensor", "humidity"]), None);
+        assert_eq!(declared_type(&schema, &["co2"]), None);
+    }
+
+    #[test]
+    fn builds_payload_from_query_parameters() {
+        let payload = url_payload(
+            &params(&[
+                ("co2", "612"),
+                ("room", "office"),
+                ("window.open", "true"),
+                ("window.angle", "12.5"),
+                ("timestamp", "2024-10-01T06:00:00Z"),
+                ("idempotency_key", "abc"),
+            ]),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            payload,
+            json!({
+                "co2": 612,
+                "room": "office",
+                "window": { "open": true, "angle": 12.5 }
+            })
+        );
+    }
+
+    #[test]
+    fn payload_uses_schema_types() {
+        let schema = json!({
+            "properties": {
+                "zip": { "type": "string" },
+                "sensor": { "properties": { "id": { "type": "string" } } }
+            }
+        });
+        let payload = url_payload(
+            &params(&[("zip", "8001"), ("sensor.id", "42"), ("sensor.co2", "612")]),
+            Some(&schema),
+        )
+        .unwrap();
+
+        assert_eq!(
+            payload,
+            json!({ "zip": "8001", "sensor": { "id": "42", "co2": 612 } })
+        );
+    }
+
+    #[test]
+    fn conflicting_parameters_are_rejected() {
+        for pairs in [
+            [("sensor", "1"), ("sensor.co2", "612")],
+            [("sensor.co2", "612"), ("sensor.co2.max", "1000")],
+        ] {
+            let error = url_payload(&params(&pairs), None).err().unwrap();
+            assert!(
+                matches!(&error, AppError::InputError(e) if e.starts_with("Conflicting parameter")),
+                "{:?}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn only_reserved_parameters_give_an_empty_payload() {
+        let payload = url_payload(&params(&[("timestamp", "2024-10-01T06:00:00Z")]), None);
+        assert_eq!(payload.unwrap(), json!({}));
+    }
+}
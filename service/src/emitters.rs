@@ -1,9 +1,22 @@
-use axum::{extract::State, http::StatusCode, Json};
+use std::{collections::HashMap, str::FromStr};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
 use duckdb::params;
+use jiff::{Span, Timestamp, Zoned};
 use serde::{Deserialize, Serialize};
 use tracing::{error, info};
 
-use crate::{auth::AuthenticatedUser, error::AppError, utils::get_auth_token, AppState};
+use crate::{
+    auth::AuthenticatedUser, error::AppError, limits::EmitterLimits, utils::get_auth_token,
+    AppState,
+};
+
+// Days of write activity listed per emitter
+const ACTIVITY_DAYS: i64 = 30;
 
 #[tracing::instrument(skip_all)]
 pub async fn get_emitters(
@@ -12,21 +25,121 @@ pub async fn get_emitters(
 ) -> Result<Json<Vec<Emitter>>, AppError> {
     let conn = state.connection.lock().await;
 
-    let mut stmt = conn.prepare("SELECT description, token FROM emitters;")?;
-    let response: Result<Vec<Emitter>, _> = stmt
+    let mut stmt = conn.prepare(
+        "SELECT emitter, cast(day as Text), bucket, writes FROM emitter_activity WHERE day > current_date - CAST((?) as INTEGER) ORDER BY day DESC, bucket;",
+    )?;
+    let activity: Result<Vec<(String, EmitterActivity)>, _> = stmt
+        .query_map(params![ACTIVITY_DAYS], |row| {
+            Ok((
+                row.get(0)?,
+                EmitterActivity {
+                    day: row.get(1)?,
+                    bucket: row.get(2)?,
+                    writes: row.get(3)?,
+                },
+            ))
+        })?
+        .collect();
+
+    let mut activity_by_emitter: HashMap<String, Vec<EmitterActivity>> = HashMap::new();
+    for (emitter, activity) in activity? {
+        activity_by_emitter
+            .entry(emitter)
+            .or_default()
+            .push(activity);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT description, token, CAST(allowed_buckets as Text), coalesce(timestamp_corrections, 0), cast(last_seen as Text), last_ip, requests_per_minute, max_body_bytes, max_points_per_day, coalesce(rate_limited, 0), coalesce(body_too_large, 0), coalesce(quota_exceeded, 0) FROM emitters;",
+    )?;
+    let response: Result<Vec<(Option<String>, Emitter)>, _> = stmt
         .query_map([], |row| {
-            Ok(Emitter {
+            Ok((
+                row.get(2)?,
+                Emitter {
+                    description: row.get(0)?,
+                    token: row.get(1)?,
+                    allowed_buckets: None,
+                    timestamp_corrections: row.get(3)?,
+                    last_seen: row.get(4)?,
+                    last_ip: row.get(5)?,
+                    activity: Vec::new(),
+                    limits: EmitterLimits {
+                        requests_per_minute: row.get(6)?,
+                        max_body_bytes: row.get(7)?,
+                        max_points_per_day: row.get(8)?,
+                    },
+                    limit_violations: LimitViolations {
+                        rate_limited: row.get(9)?,
+                        body_too_large: row.get(10)?,
+                        quota_exceeded: row.get(11)?,
+                    },
+                },
+            ))
+        })?
+        .collect();
+
+    let response = response?
+        .into_iter()
+        .map(|(allowed_buckets, mut emitter)| {
+            emitter.allowed_buckets = allowed_buckets
+                .map(|buckets| serde_json::from_str(&buckets))
+                .transpose()?;
+            emitter.last_seen = format_timestamp(emitter.last_seen)?;
+            emitter.activity = activity_by_emitter
+                .remove(&emitter.description)
+                .unwrap_or_default();
+            Ok(emitter)
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    Ok(Json(response))
+}
+
+/// Emitters that haven't authenticated for longer than the given ISO 8601
+/// duration, e.g. `PT6H` or `P2D`, including the ones never seen
+#[tracing::instrument(skip_all)]
+pub async fn get_silent_emitters(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Query(filter): Query<SilentEmitterFilter>,
+) -> Result<Json<Vec<SilentEmitter>>, AppError> {
+    let span = Span::from_str(&filter.r#for).map_err(AppError::DateInputError)?;
+    let cutoff = Zoned::now().checked_sub(span)?.timestamp().to_string();
+
+    let conn = state.connection.lock().await;
+    let mut stmt = conn.prepare(
+        "SELECT description, cast(last_seen as Text), last_ip FROM emitters WHERE last_seen IS NULL OR last_seen < CAST((?) as TIMESTAMPTZ) ORDER BY last_seen ASC NULLS FIRST;",
+    )?;
+    let response: Result<Vec<SilentEmitter>, _> = stmt
+        .query_map(params![cutoff], |row| {
+            Ok(SilentEmitter {
                 description: row.get(0)?,
-                token: row.get(1)?,
+                last_seen: row.get(1)?,
+                last_ip: row.get(2)?,
             })
         })?
         .collect();
 
-    let response = response?;
+    let response = response?
+        .into_iter()
+        .map(|mut emitter| {
+            emitter.last_seen = format_timestamp(emitter.last_seen)?;
+            Ok(emitter)
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
 
     Ok(Json(response))
 }
 
+/// Format dates in DB like `get_data` does
+fn format_timestamp(timestamp: Option<String>) -> Result<Option<String>, AppError> {
+    Ok(timestamp
+        .map(|ts| Timestamp::from_str(&ts))
+        .transpose()?
+        .map(|ts| ts.to_string()))
+}
+
 #[tracing::instrument(skip_all, fields( emitter = %request.description))]
 pub async fn add_emitter(
     State(state): State<AppState>,
@@ -48,16 +161,79 @@ pub async fn add_emitter(
         return Err(AppError::Status(StatusCode::BAD_REQUEST));
     }
 
-    let mut stmt = conn.prepare("INSERT INTO emitters (token, description) VALUES (?, ?);")?;
+    let mut stmt = conn.prepare(
+        "INSERT INTO emitters (token, description, allowed_buckets, requests_per_minute, max_body_bytes, max_points_per_day) VALUES (?, ?, ?, ?, ?, ?);",
+    )?;
     let token = get_auth_token();
-    stmt.execute(params![token, request.description])?;
+    let allowed_buckets = request
+        .allowed_buckets
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    check_limits(&request.limits)?;
+    stmt.execute(params![
+        token,
+        request.description,
+        allowed_buckets,
+        request.limits.requests_per_minute,
+        request.limits.max_body_bytes,
+        request.limits.max_points_per_day
+    ])?;
 
     Ok(Json(AddEmitterResponse {
         token,
         description: request.description,
+        allowed_buckets: request.allowed_buckets,
+        limits: request.limits,
     }))
 }
 
+#[tracing::instrument(skip_all, fields( emitter = %request.description))]
+pub async fn update_emitter(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<UpdateEmitterRequest>,
+) -> Result<StatusCode, AppError> {
+    let conn = state.connection.lock().await;
+
+    let allowed_buckets = request
+        .allowed_buckets
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    check_limits(&request.limits)?;
+    let affected_rows = conn.execute(
+        "UPDATE emitters SET allowed_buckets = (?), requests_per_minute = (?), max_body_bytes = (?), max_points_per_day = (?) WHERE description = (?);",
+        params![
+            allowed_buckets,
+            request.limits.requests_per_minute,
+            request.limits.max_body_bytes,
+            request.limits.max_points_per_day,
+            request.description
+        ],
+    )?;
+
+    if affected_rows == 0 {
+        error!(message = "emitter does not exist");
+        return Err(AppError::Status(StatusCode::NOT_FOUND));
+    }
+
+    Ok(StatusCode::OK)
+}
+
+fn check_limits(limits: &EmitterLimits) -> Result<(), AppError> {
+    let values = [
+        limits.requests_per_minute,
+        limits.max_body_bytes,
+        limits.max_points_per_day,
+    ];
+    if values.iter().flatten().any(|value| *value < 0) {
+        return Err(AppError::InputError("Limits must not be negative".into()));
+    }
+
+    Ok(())
+}
+
 #[tracing::instrument(skip_all, fields( emitter = %request.description))]
 pub async fn delete_emitter(
     State(state): State<AppState>,
@@ -70,6 +246,10 @@ pub async fn delete_emitter(
         "DELETE FROM emitters WHERE description = (?);",
         params![request.description],
     )?;
+    conn.execute(
+        "DELETE FROM emitter_activity WHERE emitter = (?);",
+        params![request.description],
+    )?;
 
     info!(message = "Deleted rows", affected_rows);
 
@@ -88,16 +268,284 @@ pub struct DeleteEmitterRequest {
 #[derive(Deserialize)]
 pub struct AddEmitterRequest {
     description: String,
+    // bucket glob patterns the emitter may write to, unrestricted if not set
+    allowed_buckets: Option<Vec<String>>,
+    #[serde(default)]
+    limits: EmitterLimits,
+}
+
+/// Replaces the emitter's allowlist and limits, unset values remove them
+#[derive(Deserialize)]
+pub struct UpdateEmitterRequest {
+    description: String,
+    allowed_buckets: Option<Vec<String>>,
+    #[serde(default)]
+    limits: EmitterLimits,
 }
 
 #[derive(Debug, Serialize)]
 pub struct AddEmitterResponse {
     description: String,
     token: String,
+    allowed_buckets: Option<Vec<String>>,
+    limits: EmitterLimits,
 }
 
 #[derive(Debug, Serialize)]
 pub struct Emitter {
     description: String,
     token: String,
+    allowed_buckets: Option<Vec<String>>,
+    // points sent with a timestamp outside of the accepted window
+    timestamp_corrections: i64,
+    // last successful authentication
+    last_seen: Option<String>,
+    last_ip: Option<String>,
+    // stored points per day and bucket over the last `ACTIVITY_DAYS` days
+    activity: Vec<EmitterActivity>,
+    limits: EmitterLimits,
+    limit_violations: LimitViolations,
+}
+
+/// Requests rejected for exceeding the emitter's limits
+#[derive(Debug, Serialize)]
+pub struct LimitViolations {
+    rate_limited: i64,
+    body_too_large: i64,
+    quota_exceeded: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmitterActivity {
+    day: String,
+    bucket: String,
+    writes: i64,
+}
+
+#[derive(Deserialize)]
+pub struct SilentEmitterFilter {
+    // ISO 8601 duration
+    r#for: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SilentEmitter {
+    description: String,
+    last_seen: Option<String>,
+    last_ip: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::{tz::TimeZone, SignedDuration};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::ingest::Point;
+
+    async fn test_state(emitters: &[&str]) -> AppState {
+        let state = AppState::in_memory().await;
+        for description in emitters {
+            state
+                .connection
+                .lock()
+                .await
+                .execute(
+                    "INSERT INTO emitters (token, description) VALUES (?, ?);",
+                    params![format!("{}-token", description), description],
+                )
+                .unwrap();
+        }
+
+        state
+    }
+
+    fn point(bucket: &str) -> Point {
+        Point {
+            timestamp: Timestamp::now().to_string(),
+            bucket: bucket.into(),
+            payload: json!({ "value": 1 }),
+            idempotency_key: None,
+            original_timestamp: None,
+        }
+    }
+
+    async fn emitters(state: &AppState) -> Value {
+        let Json(emitters) = get_emitters(State(state.clone()), AuthenticatedUser {})
+            .await
+            .unwrap();
+        serde_json::to_value(emitters).unwrap()
+    }
+
+    async fn silent_emitters(state: &AppState, duration: &str) -> Vec<String> {
+        let filter = SilentEmitterFilter {
+            r#for: duration.into(),
+        };
+        let Json(emitters) =
+            get_silent_emitters(State(state.clone()), AuthenticatedUser {}, Query(filter))
+                .await
+                .unwrap();
+        emitters
+            .into_iter()
+            .map(|emitter| emitter.description)
+            .collect()
+    }
+
+    fn update(description: &str, limits: EmitterLimits) -> Json<UpdateEmitterRequest> {
+        Json(UpdateEmitterRequest {
+            description: description.into(),
+            allowed_buckets: Some(vec!["co2".into()]),
+            limits,
+        })
+    }
+
+    #[tokio::test]
+    async fn lists_recent_activity_per_emitter() {
+        let state = test_state(&["sensor", "idle"]).await;
+        state
+            .writer
+            .write(
+                Some("sensor"),
+                vec![point("co2"), point("co2"), point("climate")],
+            )
+            .await
+            .unwrap();
+        state
+            .writer
+            .write(Some("sensor"), vec![point("co2")])
+            .await
+            .unwrap();
+        state
+            .connection
+            .lock()
+            .await
+            .execute_batch(
+                "INSERT INTO emitter_activity VALUES ('sensor', current_date - 1, 'co2', 5);
+                 INSERT INTO emitter_activity VALUES ('sensor', current_date - 40, 'co2', 7);",
+            )
+            .unwrap();
+
+        let emitters = emitters(&state).await;
+        let today = Timestamp::now().to_zoned(TimeZone::UTC).date();
+        let yesterday = today.yesterday().unwrap();
+        let sensor = emitters
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|emitter| emitter["description"] == "sensor")
+            .unwrap();
+        assert_eq!(
+            sensor["activity"],
+            json!([
+                { "day": today.to_string(), "bucket": "climate", "writes": 1 },
+                { "day": today.to_string(), "bucket": "co2", "writes": 3 },
+                { "day": yesterday.to_string(), "bucket": "co2", "writes": 5 },
+            ])
+        );
+
+        let idle = emitters
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|emitter| emitter["description"] == "idle")
+            .unwrap();
+        assert_eq!(idle["activity"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn lists_emitters_silent_for_the_duration() {
+        let state = test_state(&["never", "stale", "recent"]).await;
+        let now = Timestamp::now();
+        for (description, seen_ago) in [("stale", 48 * 3600), ("recent", 3600)] {
+            let last_seen = now - SignedDuration::from_secs(seen_ago);
+            state
+                .connection
+                .lock()
+                .await
+                .execute(
+                    "UPDATE emitters SET last_seen = CAST((?) as TIMESTAMPTZ) WHERE description = (?);",
+                    params![last_seen.to_string(), description],
+                )
+                .unwrap();
+        }
+
+        assert_eq!(silent_emitters(&state, "P1D").await, ["never", "stale"]);
+        assert_eq!(silent_emitters(&state, "PT6H").await, ["never", "stale"]);
+        assert_eq!(
+            silent_emitters(&state, "PT30M").await,
+            ["never", "stale", "recent"]
+        );
+        assert_eq!(silent_emitters(&state, "P3D").await, ["never"]);
+
+        let filter = SilentEmitterFilter {
+            r#for: "two days".into(),
+        };
+        let result =
+            get_silent_emitters(State(state.clone()), AuthenticatedUser {}, Query(filter)).await;
+        assert!(matches!(result, Err(AppError::DateInputError(_))));
+    }
+
+    #[tokio::test]
+    async fn updates_allowlist_and_limits() {
+        let state = test_state(&["sensor"]).await;
+        let limits = EmitterLimits {
+            requests_per_minute: Some(60),
+            max_body_bytes: None,
+            max_points_per_day: Some(1000),
+        };
+
+        let status = update_emitter(
+            State(state.clone()),
+            AuthenticatedUser {},
+            update("sensor", limits),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        let emitters = emitters(&state).await;
+        assert_eq!(emitters[0]["allowed_buckets"], json!(["co2"]));
+        assert_eq!(
+            emitters[0]["limits"],
+            json!({ "requests_per_minute": 60, "max_body_bytes": null, "max_points_per_day": 1000 })
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_negative_limits() {
+        let state = test_state(&["sensor"]).await;
+        let limits = EmitterLimits {
+            requests_per_minute: Some(60),
+            max_body_bytes: Some(-1),
+            max_points_per_day: None,
+        };
+
+        let result = update_emitter(
+            State(state.clone()),
+            AuthenticatedUser {},
+            update("sensor", limits),
+        )
+        .await;
+        assert!(matches!(result, Err(AppError::InputError(_))));
+
+        let emitters = emitters(&state).await;
+        assert_eq!(emitters[0]["allowed_buckets"], Value::Null);
+        assert_eq!(emitters[0]["limits"]["requests_per_minute"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn updating_an_unknown_emitter_is_not_found() {
+        let state = test_state(&["sensor"]).await;
+
+        let result = update_emitter(
+            State(state.clone()),
+            AuthenticatedUser {},
+            update("unknown", EmitterLimits::default()),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(AppError::Status(StatusCode::NOT_FOUND))
+        ));
+    }
 }
@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header::RETRY_AFTER, StatusCode},
     response::{IntoResponse, Response},
 };
 use thiserror::Error;
@@ -17,6 +17,14 @@ pub enum AppError {
     DBError(#[from] duckdb::Error),
     #[error("Serde error {0}")]
     SerdeError(#[from] serde_json::Error),
+    #[error("Input error {0}")]
+    InputError(String),
+    #[error("Validation error {0}")]
+    ValidationError(String),
+    #[error("Rate limit exceeded, retry after {0}s")]
+    RateLimited(u64),
+    #[error("Payload too large, limit is {0} bytes")]
+    PayloadTooLarge(u64),
 }
 
 impl IntoResponse for AppError {
@@ -37,6 +45,19 @@ impl IntoResponse for AppError {
             AppError::SerdeError(error) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response()
             }
+            AppError::InputError(error) => (StatusCode::BAD_REQUEST, error).into_response(),
+            AppError::ValidationError(error) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, error).into_response()
+            }
+            AppError::RateLimited(retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.to_string())],
+                self.to_string(),
+            )
+                .into_response(),
+            AppError::PayloadTooLarge(_) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()).into_response()
+            }
         }
     }
 }
@@ -2,42 +2,176 @@ use std::str::FromStr;
 
 use axum::{
     extract::{Path, State},
+    http::HeaderMap,
     Json,
 };
 use duckdb::params;
 use jiff::Timestamp;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Map, Value};
 
-use crate::{auth::AuthenticatedEmitter, error::AppError, AppState};
+use crate::{
+    auth::AuthenticatedEmitter,
+    error::AppError,
+    ingest::{
+        apply_timestamp_policy, bucket_validator, derive_idempotency_key, idempotency_key,
+        timestamp_policy, validate_payload, Point,
+    },
+    transforms::Transforms,
+    AppState,
+};
 
 #[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
 pub async fn upload_gps_data(
     State(state): State<AppState>,
     emitter: AuthenticatedEmitter,
+    headers: HeaderMap,
     Path((_, bucket)): Path<(String, String)>,
     Json(payload): Json<GPSData>,
 ) -> Result<Json<GPSUploadResponse>, AppError> {
+    emitter.authorize_bucket(&bucket)?;
+
+    let request_key = idempotency_key(&headers);
     let conn = state.connection.lock().await;
-    let mut stmt =
-        conn.prepare("INSERT INTO timeseries (timestamp, bucket, payload) VALUES (?, ?, ?);")?;
-    for location in payload.locations {
-        let payload: String = serde_json::to_string(&location)?;
+    let validator = bucket_validator(&conn, &bucket)?;
+    let policy = timestamp_policy(&conn, state.timestamp_policy.as_ref(), &bucket)?;
+    let mut transforms = Transforms::default();
+
+    let mut points = Vec::new();
+    for (index, location) in payload.locations.into_iter().enumerate() {
         let timestamp = match location.properties["timestamp"].as_str() {
             Some(ts) => Timestamp::from_str(ts)
                 .map_err(|e| AppError::DateInputError(e))?
                 .to_string(),
             None => Timestamp::now().to_string(),
         };
+        let mut point = Point {
+            timestamp,
+            bucket: bucket.clone(),
+            payload: serde_json::to_value(&location)?,
+            idempotency_key: derive_idempotency_key(&request_key, index),
+            original_timestamp: None,
+        };
 
-        stmt.execute(params![timestamp, bucket, payload])?;
+        apply_timestamp_policy(&conn, policy.as_ref(), &emitter.description, &mut point)?;
+        transforms.apply(&conn, &mut point)?;
+        validate_payload(validator.as_ref(), &point.payload)?;
+        points.push(point);
     }
 
+    drop(conn);
+
+    // Only write once all locations passed validation
+    state
+        .writer
+        .write(Some(&emitter.description), points)
+        .await?;
+
     Ok(Json(GPSUploadResponse {
         result: "ok".into(),
     }))
 }
 
+/// Accepts OwnTracks HTTP messages. Locations are stored as the same GeoJSON
+/// features Overland uploads, other message types are acknowledged and ignored.
+/// The response contains cards and latest locations of the other OwnTracks
+/// buckets the emitter may access so they show up as friends in the app.
+#[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
+pub async fn upload_owntracks_data(
+    State(state): State<AppState>,
+    emitter: AuthenticatedEmitter,
+    headers: HeaderMap,
+    Path((_, bucket)): Path<(String, String)>,
+    Json(message): Json<OwnTracksMessage>,
+) -> Result<Json<Vec<Value>>, AppError> {
+    emitter.authorize_bucket(&bucket)?;
+
+    let mut conn = state.connection.lock().await;
+
+    if let OwnTracksMessage::Location(location) = message {
+        let timestamp = Timestamp::from_second(location.tst)
+            .map_err(AppError::DateInputError)?
+            .to_string();
+
+        let mut properties = Map::new();
+        properties.insert("timestamp".into(), timestamp.clone().into());
+        if let Some(acc) = location.acc {
+            properties.insert("horizontal_accuracy".into(), acc.into());
+        }
+        if let Some(alt) = location.alt {
+            properties.insert("altitude".into(), alt.into());
+        }
+        if let Some(batt) = location.batt {
+            // Overland reports battery level in [0, 1]
+            properties.insert("battery_level".into(), (batt / 100.0).into());
+        }
+        if let Some(vel) = location.vel {
+            // OwnTracks reports km/h, Overland m/s
+            properties.insert("speed".into(), (vel / 3.6).into());
+        }
+        if let Some(tid) = location.tid {
+            properties.insert("tid".into(), tid.into());
+        }
+
+        let feature = GPSLocation::point(location.lon, location.lat, properties);
+        let mut point = Point {
+            timestamp,
+            bucket: bucket.clone(),
+            payload: serde_json::to_value(&feature)?,
+            idempotency_key: idempotency_key(&headers),
+            original_timestamp: None,
+        };
+
+        let policy = timestamp_policy(&conn, state.timestamp_policy.as_ref(), &bucket)?;
+        apply_timestamp_policy(&conn, policy.as_ref(), &emitter.description, &mut point)?;
+
+        Transforms::default().apply(&conn, &mut point)?;
+        let validator = bucket_validator(&conn, &bucket)?;
+        validate_payload(validator.as_ref(), &point.payload)?;
+
+        // The writer needs the connection, take it back for the friends query
+        drop(conn);
+        state
+            .writer
+            .write(Some(&emitter.description), vec![point])
+            .await?;
+        conn = state.connection.lock().await;
+    }
+
+    // Only OwnTracks uploads carry a tracker id, use it to find the friends' buckets
+    let mut stmt = conn.prepare(
+        "SELECT bucket, cast(payload -> '$.geometry.coordinates[0]' as double), cast(payload -> '$.geometry.coordinates[1]' as double), cast(epoch(timestamp) as BIGINT), payload ->> '$.properties.tid' FROM timeseries WHERE bucket != (?) AND payload ->> '$.properties.tid' IS NOT NULL QUALIFY row_number() OVER (PARTITION BY bucket ORDER BY timestamp DESC) = 1;",
+    )?;
+
+    let friends: Result<Vec<FriendRow>, _> = stmt
+        .query_map(params![bucket], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?
+        .collect();
+
+    let mut response = Vec::new();
+    // Restricted emitters only see friends in buckets they have access to
+    for (bucket, lon, lat, tst, tid) in friends?
+        .into_iter()
+        .filter(|(bucket, ..)| emitter.may_write(bucket))
+    {
+        response.push(json!({ "_type": "card", "tid": tid, "name": bucket }));
+        response
+            .push(json!({ "_type": "location", "tid": tid, "lat": lat, "lon": lon, "tst": tst }));
+    }
+
+    Ok(Json(response))
+}
+
+// bucket, longitude, latitude, epoch seconds and tracker id of a friend's last location
+type FriendRow = (String, f64, f64, i64, String);
+
 #[derive(Debug, Serialize)]
 pub struct GPSUploadResponse {
     result: String,
@@ -50,13 +184,48 @@ struct GPSGeometry {
 }
 
 #[derive(Deserialize, Serialize, Clone)]
-struct GPSLocation {
+pub(crate) struct GPSLocation {
     properties: Value,
     r#type: String,
     geometry: GPSGeometry,
 }
 
+impl GPSLocation {
+    /// GeoJSON point feature in the shape Overland uploads
+    pub(crate) fn point(longitude: f64, latitude: f64, properties: Map<String, Value>) -> Self {
+        GPSLocation {
+            properties: Value::Object(properties),
+            r#type: "Feature".into(),
+            geometry: GPSGeometry {
+                r#type: "Point".into(),
+                coordinates: [longitude, latitude],
+            },
+        }
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct GPSData {
     locations: Vec<GPSLocation>,
 }
+
+#[derive(Deserialize)]
+#[serde(tag = "_type", rename_all = "lowercase")]
+pub enum OwnTracksMessage {
+    Location(OwnTracksLocation),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+pub struct OwnTracksLocation {
+    lat: f64,
+    lon: f64,
+    // seconds since epoch
+    tst: i64,
+    acc: Option<f64>,
+    alt: Option<f64>,
+    batt: Option<f64>,
+    vel: Option<f64>,
+    tid: Option<String>,
+}
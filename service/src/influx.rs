@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+};
+use jiff::Timestamp;
+use jsonschema::Validator;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use tracing::info;
+
+use crate::{
+    auth::AuthenticatedEmitter,
+    error::AppError,
+    ingest::{bucket_validator, validate_payload, Point},
+    transforms::Transforms,
+    AppState,
+};
+
+/// Accepts InfluxDB line protocol. The measurement is used as bucket, tags and
+/// fields are merged into the payload with fields taking precedence.
+#[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
+pub async fn upload_line_protocol(
+    State(state): State<AppState>,
+    emitter: AuthenticatedEmitter,
+    Query(params): Query<WriteParams>,
+    body: String,
+) -> Result<StatusCode, AppError> {
+    let precision = params.precision.unwrap_or("ns".into());
+    let nanos_per_unit = nanos_per_unit(&precision)
+        .ok_or_else(|| AppError::InputError(format!("Unsupported precision {}", precision)))?;
+
+    let mut points = Vec::new();
+    for (number, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let point = parse_line(line, nanos_per_unit)
+            .map_err(|e| AppError::InputError(format!("Line {}: {}", number + 1, e)))?;
+        emitter.authorize_bucket(&point.bucket)?;
+        points.push(point);
+    }
+
+    let conn = state.connection.lock().await;
+    let mut transforms = Transforms::default();
+    let mut validators: HashMap<String, Option<Validator>> = HashMap::new();
+    for point in points.iter_mut() {
+        transforms.apply(&conn, point)?;
+        if !validators.contains_key(&point.bucket) {
+            let validator = bucket_validator(&conn, &point.bucket)?;
+            validators.insert(point.bucket.clone(), validator);
+        }
+        validate_payload(validators[&point.bucket].as_ref(), &point.payload)?;
+    }
+    drop(conn);
+
+    let outcomes = state
+        .writer
+        .write(Some(&emitter.description), points)
+        .await?;
+
+    info!(
+        message = "Wrote line protocol points",
+        points = outcomes.len()
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Nanoseconds per timestamp unit of the given precision
+fn nanos_per_unit(precision: &str) -> Option<i128> {
+    match precision {
+        "ns" | "n" => Some(1),
+        "us" | "u" => Some(1_000),
+        "ms" => Some(1_000_000),
+        "s" => Some(1_000_000_000),
+        _ => None,
+    }
+}
+
+fn parse_line(line: &str, nanos_per_unit: i128) -> Result<Point, String> {
+    let parts = split_unescaped(line, ' ', true);
+    let (series, fields, timestamp) = match parts.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+        _ => return Err("Expected measurement, field set and optional timestamp".into()),
+    };
+
+    let mut series = split_unescaped(series, ',', false).into_iter();
+    let bucket = unescape(series.next().unwrap_or_default());
+    if bucket.is_empty() {
+        return Err("Missing measurement".into());
+    }
+
+    let mut payload = Map::new();
+    for tag in series {
+        let (key, value) = split_key_value(tag)?;
+        payload.insert(unescape(key), Value::String(unescape(value)));
+    }
+
+    for field in split_unescaped(fields, ',', true) {
+        let (key, value) = split_key_value(field)?;
+        payload.insert(unescape(key), parse_field_value(value)?);
+    }
+
+    let timestamp = match timestamp {
+        Some(ts) => {
+            let nanos = ts
+                .parse::<i128>()
+                .ok()
+                .and_then(|ts| ts.checked_mul(nanos_per_unit))
+                .ok_or_else(|| format!("Invalid timestamp {}", ts))?;
+            Timestamp::from_nanosecond(nanos)
+                .map_err(|e| e.to_string())?
+                .to_string()
+        }
+        None => Timestamp::now().to_string(),
+    };
+
+    Ok(Point {
+        timestamp,
+        bucket,
+        payload: Value::Object(payload),
+        idempotency_key: None,
+        original_timestamp: None,
+    })
+}
+
+fn split_key_value(input: &str) -> Result<(&str, &str), String> {
+    match split_unescaped(input, '=', true).as_slice() {
+        [key, value] if !key.is_empty() => Ok((*key, *value)),
+        _ => Err(format!("Invalid key value pair {}", input)),
+    }
+}
+
+fn parse_field_value(value: &str) -> Result<Value, String> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        return Ok(Value::String(unescape(&value[1..value.len() - 1])));
+    }
+
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(Value::Bool(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+
+    if let Some(integer) = value.strip_suffix('i') {
+        return integer
+            .parse::<i64>()
+            .map(Value::from)
+            .map_err(|_| format!("Invalid integer {}", value));
+    }
+
+    if let Some(unsigned) = value.strip_suffix('u') {
+        return unsigned
+            .parse::<u64>()
+            .map(Value::from)
+            .map_err(|_| format!("Invalid unsigned integer {}", value));
+    }
+
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(Number::from_f64)
+        .map(Value::Number)
+        .ok_or(format!("Invalid field value {}", value))
+}
+
+/// Split at every `separator` that is neither escaped nor, if `quotes` is set,
+/// inside a double quoted string
+fn split_unescaped(input: &str, separator: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match c {
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&input[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+
+    parts
+}
+
+fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(next @ (',' | ' ' | '=' | '"' | '\\')) => output.push(next),
+                Some(next) => {
+                    output.push(c);
+                    output.push(next);
+                }
+                None => output.push(c),
+            }
+        } else {
+            output.push(c);
+        }
+    }
+
+    output
+}
+
+#[derive(Deserialize)]
+pub struct WriteParams {
+    // timestamp precision of the lines, one of ns, us, ms, s
+    precision: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parses_tags_fields_and_timestamp() {
+        let point = parse_line(
+            "weather,location=us-midwest,season=summer temperature=82,humidity=40i 1465839830100400200",
+            1,
+        )
+        .unwrap();
+
+        assert_eq!(point.bucket, "weather");
+        assert_eq!(point.timestamp, "2016-06-13T17:43:50.1004002Z");
+        assert_eq!(
+            point.payload,
+            json!({
+                "location": "us-midwest",
+                "season": "summer",
+                "temperature": 82.0,
+                "humidity": 40
+            })
+        );
+    }
+
+    #[test]
+    fn parses_field_types() {
+        let point = parse_line(
+            r#"m float=1.5,int=-3i,unsigned=7u,string="hello, world",yes=t,no=FALSE 0"#,
+            1,
+        )
+        .unwrap();
+
+        assert_eq!(
+            point.payload,
+            json!({
+                "float": 1.5,
+                "int": -3,
+                "unsigned": 7,
+                "string": "hello, world",
+                "yes": true,
+                "no": false
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_field_values() {
+        assert!(parse_line("m value=12x 0", 1).is_err());
+        assert!(parse_line("m value=1.5i 0", 1).is_err());
+        assert!(parse_line("m value=-1u 0", 1).is_err());
+        assert!(parse_line("m 0", 1).is_err());
+        assert!(parse_line(",tag=a value=1 0", 1).is_err());
+    }
+
+    #[test]
+    fn unescapes_measurement_tags_and_strings() {
+        let point = parse_line(
+            r#"my\ measurement,tag\,key=tag\ value,eq\=key=a\=b text="say \"hi\"" 0"#,
+            1,
+        )
+        .unwrap();
+
+        assert_eq!(point.bucket, "my measurement");
+        assert_eq!(
+            point.payload,
+            json!({
+                "tag,key": "tag value",
+                "eq=key": "a=b",
+                "text": "say \"hi\""
+            })
+        );
+    }
+
+    #[test]
+    fn quoted_fields_may_contain_separators() {
+        let point = parse_line(r#"m a="x y=z,w",b=2 0"#, 1).unwrap();
+        assert_eq!(point.payload, json!({ "a": "x y=z,w", "b": 2.0 }));
+    }
+
+    #[test]
+    fn applies_precision() {
+        for (precision, timestamp) in [
+            ("ns", "1700000000000000000"),
+            ("us", "1700000000000000"),
+            ("ms", "1700000000000"),
+            ("s", "1700000000"),
+        ] {
+            let nanos = nanos_per_unit(precision).unwrap();
+            let point = parse_line(&format!("m v=1 {}", timestamp), nanos).unwrap();
+            assert_eq!(point.timestamp, "2023-11-14T22:13:20Z", "{}", precision);
+        }
+        assert_eq!(nanos_per_unit("h"), None);
+    }
+
+    #[test]
+    fn rejects_out_of_range_timestamps() {
+        let nanos = nanos_per_unit("s").unwrap();
+        let timestamp = i128::MAX / 10;
+        assert!(parse_line(&format!("m v=1 {}", timestamp), nanos).is_err());
+        assert!(parse_line("m v=1 99999999999999999999", nanos).is_err());
+    }
+
+    #[test]
+    fn missing_timestamp_uses_server_time() {
+        let before = Timestamp::now();
+        let point = parse_line("m v=1", 1).unwrap();
+        assert!(point.timestamp.parse::<Timestamp>().unwrap() >= before);
+    }
+}
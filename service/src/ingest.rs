@@ -0,0 +1,432 @@
+use std::{env, str::FromStr};
+
+use axum::http::{HeaderMap, StatusCode};
+use duckdb::{params, Connection};
+use jiff::Timestamp;
+use jsonschema::Validator;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+use crate::error::AppError;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// A data point ready to be written to the `timeseries` table
+pub struct Point {
+    pub timestamp: String,
+    pub bucket: String,
+    pub payload: Value,
+    pub idempotency_key: Option<String>,
+    // timestamp sent by the client if it was outside of the accepted window
+    pub original_timestamp: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InsertOutcome {
+    Inserted,
+    Duplicate,
+}
+
+/// A point is a duplicate if its bucket already holds a point with the same
+/// idempotency key or, with `dedupe_identical` set, one with the same
+/// timestamp and payload. `payload` is the serialized payload of the point.
+pub fn is_duplicate(
+    conn: &Connection,
+    dedupe_identical: bool,
+    point: &Point,
+    payload: &str,
+) -> Result<bool, AppError> {
+    // `timeseries_idempotency_key` serves the key lookups
+    let duplicate = if let Some(key) = &point.idempotency_key {
+        let mut stmt = conn.prepare_cached(
+            "SELECT EXISTS (SELECT 1 FROM timeseries WHERE idempotency_key = (?) AND bucket = (?));",
+        )?;
+        stmt.query_row(params![key, point.bucket], |row| row.get(0))?
+    } else if dedupe_identical {
+        let mut stmt = conn.prepare_cached(
+            "SELECT EXISTS (SELECT 1 FROM timeseries WHERE bucket = (?) AND timestamp = CAST((?) as TIMESTAMPTZ) AND CAST(payload as Text) = (?));",
+        )?;
+        stmt.query_row(params![point.bucket, point.timestamp, payload], |row| {
+            row.get(0)
+        })?
+    } else {
+        false
+    };
+
+    Ok(duplicate)
+}
+
+/// Idempotency key sent by the client for the whole request
+pub fn idempotency_key(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// Derive a key for the `index`-th point of a request carrying a single key
+pub fn derive_idempotency_key(key: &Option<String>, index: usize) -> Option<String> {
+    key.as_ref().map(|key| format!("{}:{}", key, index))
+}
+
+/// JSON Schema attached to `bucket`, if any
+pub fn bucket_schema(conn: &Connection, bucket: &str) -> Result<Option<Value>, AppError> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT CAST(json_schema as Text) FROM bucket_schemas WHERE bucket = (?);",
+    )?;
+    let mut rows = stmt.query(params![bucket])?;
+
+    let Some(row) = rows.next()? else {
+        return Ok(None);
+    };
+    let schema: String = row.get(0)?;
+
+    Ok(Some(serde_json::from_str(&schema)?))
+}
+
+/// Compiled JSON Schema attached to `bucket`, if any
+pub fn bucket_validator(conn: &Connection, bucket: &str) -> Result<Option<Validator>, AppError> {
+    bucket_schema(conn, bucket)?
+        .map(|schema| compile_schema(bucket, &schema))
+        .transpose()
+}
+
+pub fn compile_schema(bucket: &str, schema: &Value) -> Result<Validator, AppError> {
+    // Schemas are checked when they are stored, so this only fails if the DB was edited by hand
+    jsonschema::validator_for(schema).map_err(|e| {
+        error!(message = "Invalid schema stored for bucket", bucket, error = %e);
+        AppError::Status(StatusCode::INTERNAL_SERVER_ERROR)
+    })
+}
+
+/// Reject payloads that don't conform to the bucket's schema
+pub fn validate_payload(validator: Option<&Validator>, payload: &Value) -> Result<(), AppError> {
+    let Some(validator) = validator else {
+        return Ok(());
+    };
+
+    let errors = schema_errors(validator, payload);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::ValidationError(errors.join("; ")))
+    }
+}
+
+/// All violations of `payload` against the schema, prefixed by the JSON pointer of the failing value
+pub fn schema_errors(validator: &Validator, payload: &Value) -> Vec<String> {
+    validator
+        .iter_errors(payload)
+        .map(|e| {
+            let path = e.instance_path.as_str();
+            format!("{}: {}", if path.is_empty() { "/" } else { path }, e)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampAction {
+    // refuse the point
+    Reject,
+    // store the point at server time
+    Clamp,
+    // store the point as sent but keep it in `original_timestamp`
+    Flag,
+}
+
+impl TimestampAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimestampAction::Reject => "reject",
+            TimestampAction::Clamp => "clamp",
+            TimestampAction::Flag => "flag",
+        }
+    }
+}
+
+impl FromStr for TimestampAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(TimestampAction::Reject),
+            "clamp" => Ok(TimestampAction::Clamp),
+            "flag" => Ok(TimestampAction::Flag),
+            _ => Err(format!("Unknown timestamp action {}", value)),
+        }
+    }
+}
+
+/// Window around server time that client timestamps have to fall into
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TimestampPolicy {
+    pub action: TimestampAction,
+    // seconds a timestamp may lie in the past, unbounded if not set
+    pub max_past: Option<i64>,
+    // seconds a timestamp may lie in the future, unbounded if not set
+    pub max_future: Option<i64>,
+}
+
+impl TimestampPolicy {
+    /// Read the global policy from the environment. It is disabled if
+    /// `TIMESTAMP_POLICY` (`reject`, `clamp` or `flag`) is not set, the window
+    /// is configured in `TIMESTAMP_MAX_PAST` and `TIMESTAMP_MAX_FUTURE` seconds.
+    pub fn from_env() -> Option<Self> {
+        let action = match env::var("TIMESTAMP_POLICY").ok()?.parse() {
+            Ok(action) => action,
+            Err(e) => {
+                warn!(message = "Ignoring TIMESTAMP_POLICY", error = %e);
+                return None;
+            }
+        };
+        let seconds = |name: &str| env::var(name).ok().and_then(|v| v.parse().ok());
+
+        Some(TimestampPolicy {
+            action,
+            max_past: seconds("TIMESTAMP_MAX_PAST"),
+            max_future: seconds("TIMESTAMP_MAX_FUTURE"),
+        })
+    }
+}
+
+/// Timestamp policy of `bucket`, falling back to the global one
+pub fn timestamp_policy(
+    conn: &Connection,
+    global: Option<&TimestampPolicy>,
+    bucket: &str,
+) -> Result<Option<TimestampPolicy>, AppError> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT action, max_past, max_future FROM timestamp_policies WHERE bucket = (?);",
+    )?;
+    let mut rows = stmt.query(params![bucket])?;
+
+    let Some(row) = rows.next()? else {
+        return Ok(global.cloned());
+    };
+    let action: String = row.get(0)?;
+
+    Ok(Some(TimestampPolicy {
+        action: action.parse().map_err(AppError::InputError)?,
+        max_past: row.get(1)?,
+        max_future: row.get(2)?,
+    }))
+}
+
+/// Reject, clamp or flag a point whose timestamp is outside of the policy's
+/// window. Every such point is counted for the emitter that sent it.
+pub fn apply_timestamp_policy(
+    conn: &Connection,
+    policy: Option<&TimestampPolicy>,
+    emitter: &str,
+    point: &mut Point,
+) -> Result<(), AppError> {
+    let Some(policy) = policy else {
+        return Ok(());
+    };
+    if !outside_window(policy, point)? {
+        return Ok(());
+    }
+
+    conn.prepare_cached(
+        "UPDATE emitters SET timestamp_corrections = coalesce(timestamp_corrections, 0) + 1 WHERE description = (?);",
+    )?
+    .execute(params![emitter])?;
+    info!(
+        message = "Timestamp outside of accepted window",
+        timestamp = %point.timestamp,
+        action = ?policy.action
+    );
+
+    correct_timestamp(policy, point)
+}
+
+/// Apply the policy like `apply_timestamp_policy` without counting the point,
+/// for previews that must not change anything
+pub fn preview_timestamp_policy(
+    policy: Option<&TimestampPolicy>,
+    point: &mut Point,
+) -> Result<(), AppError> {
+    match policy {
+        Some(policy) if outside_window(policy, point)? => correct_timestamp(policy, point),
+        _ => Ok(()),
+    }
+}
+
+fn outside_window(policy: &TimestampPolicy, point: &Point) -> Result<bool, AppError> {
+    let offset = Timestamp::from_str(&point.timestamp)?.as_second() - Timestamp::now().as_second();
+    let too_old = policy.max_past.is_some_and(|max| offset < -max);
+    let too_new = policy.max_future.is_some_and(|max| offset > max);
+
+    Ok(too_old || too_new)
+}
+
+fn correct_timestamp(policy: &TimestampPolicy, point: &mut Point) -> Result<(), AppError> {
+    match policy.action {
+        TimestampAction::Reject => {
+            return Err(AppError::InputError(format!(
+                "Timestamp {} outside of accepted window",
+                point.timestamp
+            )))
+        }
+        TimestampAction::Clamp => {
+            point.original_timestamp = Some(point.timestamp.clone());
+            point.timestamp = Timestamp::now().to_string();
+        }
+        TimestampAction::Flag => point.original_timestamp = Some(point.timestamp.clone()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::migration::apply_migrations;
+
+    async fn test_connection() -> Arc<Mutex<Connection>> {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        apply_migrations(conn.clone()).await.unwrap();
+        conn.lock()
+            .await
+            .execute(
+                "INSERT INTO emitters (token, description) VALUES (?, ?);",
+                params!["token", "sensor"],
+            )
+            .unwrap();
+
+        conn
+    }
+
+    fn corrections(conn: &Connection) -> i64 {
+        conn.query_row(
+            "SELECT timestamp_corrections FROM emitters WHERE description = 'sensor';",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    // Point `offset` seconds away from now
+    fn point(offset: i64) -> Point {
+        Point {
+            timestamp: Timestamp::from_second(Timestamp::now().as_second() + offset)
+                .unwrap()
+                .to_string(),
+            bucket: "co2".into(),
+            payload: json!({ "co2": 612 }),
+            idempotency_key: None,
+            original_timestamp: None,
+        }
+    }
+
+    fn policy(action: TimestampAction) -> TimestampPolicy {
+        TimestampPolicy {
+            action,
+            max_past: Some(3600),
+            max_future: Some(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn points_inside_the_window_are_kept() {
+        let conn = test_connection().await;
+        let conn = conn.lock().await;
+
+        for offset in [-3600, 0, 60] {
+            let mut point = point(offset);
+            let timestamp = point.timestamp.clone();
+            apply_timestamp_policy(
+                &conn,
+                Some(&policy(TimestampAction::Reject)),
+                "sensor",
+                &mut point,
+            )
+            .unwrap();
+
+            assert_eq!(point.timestamp, timestamp);
+            assert_eq!(point.original_timestamp, None);
+        }
+
+        // Without a policy any timestamp is accepted
+        let mut point = point(-10 * 365 * 86400);
+        apply_timestamp_policy(&conn, None, "sensor", &mut point).unwrap();
+        assert_eq!(point.original_timestamp, None);
+        assert_eq!(corrections(&conn), 0);
+    }
+
+    #[tokio::test]
+    async fn reject_refuses_points_outside_the_window() {
+        let conn = test_connection().await;
+        let conn = conn.lock().await;
+        let policy = policy(TimestampAction::Reject);
+
+        for offset in [-3700, 120] {
+            let result = apply_timestamp_policy(&conn, Some(&policy), "sensor", &mut point(offset));
+            assert!(matches!(result, Err(AppError::InputError(_))));
+        }
+        assert_eq!(corrections(&conn), 2);
+    }
+
+    #[tokio::test]
+    async fn clamp_moves_points_to_server_time() {
+        let conn = test_connection().await;
+        let conn = conn.lock().await;
+
+        let mut point = point(-7200);
+        let sent = point.timestamp.clone();
+        let before = Timestamp::now();
+        apply_timestamp_policy(
+            &conn,
+            Some(&policy(TimestampAction::Clamp)),
+            "sensor",
+            &mut point,
+        )
+        .unwrap();
+
+        assert_eq!(point.original_timestamp, Some(sent));
+        assert!(Timestamp::from_str(&point.timestamp).unwrap() >= before);
+        assert_eq!(corrections(&conn), 1);
+    }
+
+    #[tokio::test]
+    async fn flag_keeps_the_timestamp() {
+        let conn = test_connection().await;
+        let conn = conn.lock().await;
+
+        let mut point = point(600);
+        let sent = point.timestamp.clone();
+        apply_timestamp_policy(
+            &conn,
+            Some(&policy(TimestampAction::Flag)),
+            "sensor",
+            &mut point,
+        )
+        .unwrap();
+
+        assert_eq!(point.timestamp, sent);
+        assert_eq!(point.original_timestamp, Some(sent));
+        assert_eq!(corrections(&conn), 1);
+    }
+
+    #[tokio::test]
+    async fn windows_are_unbounded_if_not_set() {
+        let conn = test_connection().await;
+        let conn = conn.lock().await;
+        let policy = TimestampPolicy {
+            action: TimestampAction::Reject,
+            max_past: None,
+            max_future: Some(60),
+        };
+
+        let mut old = point(-10 * 365 * 86400);
+        apply_timestamp_policy(&conn, Some(&policy), "sensor", &mut old).unwrap();
+        assert!(apply_timestamp_policy(&conn, Some(&policy), "sensor", &mut point(120)).is_err());
+    }
+}
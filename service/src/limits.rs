@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header::CONTENT_LENGTH, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use duckdb::{params, Connection};
+use futures_util::StreamExt;
+use jiff::{tz::TimeZone, Timestamp};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{error::AppError, AppState};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Limits the requests of an emitter are held to, unlimited where not set
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct EmitterLimits {
+    pub requests_per_minute: Option<i64>,
+    // checked against the Content-Length header and the body as it is read
+    pub max_body_bytes: Option<i64>,
+    // points stored per UTC day
+    pub max_points_per_day: Option<i64>,
+}
+
+pub enum Violation {
+    RateLimited,
+    BodyTooLarge,
+    QuotaExceeded,
+}
+
+/// Fixed one-minute request windows per emitter, kept in memory
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    windows: Arc<Mutex<HashMap<String, (Instant, i64)>>>,
+}
+
+impl RateLimiter {
+    /// Count a request against the emitter's current window. Returns the
+    /// seconds until the window resets if the limit is already reached.
+    fn acquire(&self, emitter: &str, limit: i64) -> Result<(), u64> {
+        self.acquire_at(emitter, limit, Instant::now())
+    }
+
+    fn acquire_at(&self, emitter: &str, limit: i64, now: Instant) -> Result<(), u64> {
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        // Expired windows would be reset anyway, dropping them keeps emitters
+        // that stopped sending from piling up
+        windows.retain(|_, (start, _)| now.duration_since(*start) < RATE_WINDOW);
+
+        let (start, count) = windows.entry(emitter.into()).or_insert((now, 0));
+        if *count >= limit {
+            let reset = RATE_WINDOW.saturating_sub(now.duration_since(*start));
+            return Err(reset.as_secs().max(1));
+        }
+        *count += 1;
+
+        Ok(())
+    }
+}
+
+/// Reject requests exceeding the emitter's limits. Every violation is counted
+/// for the emitter.
+pub fn enforce_limits(
+    conn: &Connection,
+    limiter: &RateLimiter,
+    emitter: &str,
+    limits: &EmitterLimits,
+    headers: &HeaderMap,
+) -> Result<(), AppError> {
+    if let Some(max) = limits.max_body_bytes {
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<i64>().ok());
+        if length.is_some_and(|length| length > max) {
+            count_violation(conn, emitter, Violation::BodyTooLarge)?;
+            return Err(AppError::PayloadTooLarge(max.max(0) as u64));
+        }
+    }
+
+    if let Some(limit) = limits.requests_per_minute {
+        if let Err(retry_after) = limiter.acquire(emitter, limit) {
+            count_violation(conn, emitter, Violation::RateLimited)?;
+            return Err(AppError::RateLimited(retry_after));
+        }
+    }
+
+    // The writer checks the points of each request against what is left,
+    // this only rejects emitters that used up their quota early
+    if let Some(max) = limits.max_points_per_day {
+        if points_written_today(conn, emitter)? >= max {
+            count_violation(conn, emitter, Violation::QuotaExceeded)?;
+            return Err(AppError::RateLimited(seconds_until_quota_reset()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Points the emitter may still store today, `None` if it has no quota
+pub fn remaining_points(conn: &Connection, emitter: &str) -> Result<Option<i64>, AppError> {
+    let mut stmt =
+        conn.prepare_cached("SELECT max_points_per_day FROM emitters WHERE description = (?);")?;
+    let mut rows = stmt.query(params![emitter])?;
+    let max: Option<i64> = match rows.next()? {
+        Some(row) => row.get(0)?,
+        None => None,
+    };
+
+    match max {
+        Some(max) => Ok(Some(max - points_written_today(conn, emitter)?)),
+        None => Ok(None),
+    }
+}
+
+fn points_written_today(conn: &Connection, emitter: &str) -> Result<i64, AppError> {
+    let today = Timestamp::now().to_zoned(TimeZone::UTC).date().to_string();
+    let written = conn
+        .prepare_cached(
+            "SELECT coalesce(sum(writes), 0) FROM emitter_activity WHERE emitter = (?) AND day = CAST((?) as DATE);",
+        )?
+        .query_row(params![emitter, today], |row| row.get(0))?;
+
+    Ok(written)
+}
+
+/// The daily quota resets at midnight UTC
+pub fn seconds_until_quota_reset() -> u64 {
+    let now = Timestamp::now().as_second();
+    (SECONDS_PER_DAY - now.rem_euclid(SECONDS_PER_DAY)) as u64
+}
+
+/// Body limit of the emitter a request is authenticated as. The
+/// `AuthenticatedEmitter` extractor sets it before the body is read.
+#[derive(Clone, Default)]
+pub struct BodyLimit(Arc<Mutex<BodyLimitState>>);
+
+#[derive(Default)]
+struct BodyLimitState {
+    emitter: String,
+    max: Option<u64>,
+    exceeded: bool,
+}
+
+impl BodyLimit {
+    pub fn set(&self, emitter: &str, max: u64) {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        state.emitter = emitter.into();
+        state.max = Some(max);
+    }
+
+    /// Whether `read` bytes are over the limit, remembered for `limit_body`
+    fn check(&self, read: u64) -> bool {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if state.max.is_some_and(|max| read > max) {
+            state.exceeded = true;
+        }
+        state.exceeded
+    }
+
+    fn exceeded(&self) -> Option<(String, u64)> {
+        let state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match (state.exceeded, state.max) {
+            (true, Some(max)) => Some((state.emitter.clone(), max)),
+            _ => None,
+        }
+    }
+}
+
+/// Enforce the emitter's `max_body_bytes` on the body as it is read, which
+/// covers bodies without `Content-Length`, like chunked NDJSON uploads. Inside
+/// the decompression layer the decompressed size is limited.
+pub async fn limit_body(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let limit = BodyLimit::default();
+    let (mut parts, body) = request.into_parts();
+    parts.extensions.insert(limit.clone());
+
+    let counter = limit.clone();
+    let mut read = 0;
+    let body = Body::from_stream(body.into_data_stream().map(move |chunk| {
+        // Unwrapped, so extractors still see the global limit's `LengthLimitError`
+        let chunk = chunk.map_err(axum::Error::into_inner)?;
+        read += chunk.len() as u64;
+        if counter.check(read) {
+            return Err("Body exceeds the emitter's limit".into());
+        }
+        Ok::<_, BoxError>(chunk)
+    }));
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    // Body extractors reject the failed body with their own status
+    let Some((emitter, max)) = limit.exceeded() else {
+        return response;
+    };
+    let conn = state.connection.lock().await;
+    if let Err(e) = count_violation(&conn, &emitter, Violation::BodyTooLarge) {
+        return e.into_response();
+    }
+    AppError::PayloadTooLarge(max).into_response()
+}
+
+pub fn count_violation(
+    conn: &Connection,
+    emitter: &str,
+    violation: Violation,
+) -> Result<(), AppError> {
+    let (kind, query) = match violation {
+        Violation::RateLimited => (
+            "rate",
+            "UPDATE emitters SET rate_limited = coalesce(rate_limited, 0) + 1 WHERE description = (?);",
+        ),
+        Violation::BodyTooLarge => (
+            "body size",
+            "UPDATE emitters SET body_too_large = coalesce(body_too_large, 0) + 1 WHERE description = (?);",
+        ),
+        Violation::QuotaExceeded => (
+            "daily points",
+            "UPDATE emitters SET quota_exceeded = coalesce(quota_exceeded, 0) + 1 WHERE description = (?);",
+        ),
+    };
+
+    warn!(message = "Emitter exceeded limit", limit = kind);
+    conn.prepare_cached(query)?.execute(params![emitter])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_resets_and_evicts_windows() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        assert_eq!(limiter.acquire_at("a", 2, start), Ok(()));
+        assert_eq!(limiter.acquire_at("a", 2, start), Ok(()));
+        let later = start + Duration::from_secs(15);
+        assert_eq!(limiter.acquire_at("a", 2, later), Err(45));
+        let later = start + Duration::from_secs(30);
+        assert_eq!(limiter.acquire_at("b", 1, later), Ok(()));
+
+        // The window of a expired and is dropped, the one of b still counts
+        let later = start + RATE_WINDOW;
+        assert_eq!(limiter.acquire_at("b", 1, later), Err(30));
+        assert_eq!(limiter.windows.lock().unwrap().len(), 1);
+        assert_eq!(limiter.acquire_at("a", 2, later), Ok(()));
+    }
+
+    #[test]
+    fn body_limit_only_applies_once_set() {
+        let limit = BodyLimit::default();
+        assert!(!limit.check(u64::MAX));
+        assert_eq!(limit.exceeded(), None);
+
+        limit.set("sensor", 10);
+        assert!(!limit.check(10));
+        assert!(limit.check(11));
+        assert_eq!(limit.exceeded(), Some(("sensor".into(), 10)));
+    }
+}
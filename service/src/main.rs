@@ -1,43 +1,143 @@
-use std::{env, process::abort, sync::Arc, time::Duration};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    process::abort,
+    sync::Arc,
+    time::Duration,
+};
 
+use aggregate::get_aggregated_data;
+use auth::trusted_proxies_from_env;
 use axum::{
     body::Body,
+    extract::DefaultBodyLimit,
     http::{Request, Response, StatusCode},
-    routing::{delete, get, post},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
 use buckets::get_distinct_buckets;
-use data::{delete_data, get_data, upload_data, upload_data_url_only};
+use csv_import::import_csv;
+use data::{
+    delete_data, get_data, get_latest_data, upload_data, upload_data_batch, upload_data_ndjson,
+    upload_data_url_only,
+};
 use duckdb::Connection;
-use emitters::{add_emitter, delete_emitter, get_emitters};
+use emitters::{add_emitter, delete_emitter, get_emitters, get_silent_emitters, update_emitter};
 use endpoints::{
     location::get_gps_coords, observatory::get_observatory_info, sensors::get_co2,
     weight::get_weight,
 };
 use error::AppError;
-use gps::upload_gps_data;
+use gps::{upload_gps_data, upload_owntracks_data};
+use influx::upload_line_protocol;
+use ingest::TimestampPolicy;
+use limits::{limit_body, RateLimiter};
 use migration::apply_migrations;
+use mqtt::{run_mqtt_subscriber, MqttConfig};
+use pollers::{delete_poller, get_pollers, run_pollers, set_poller};
+use prometheus::upload_remote_write;
+use schemas::{check_schema, delete_schema, get_schemas, set_schema};
 use spa::static_handler;
+use timestamp_policies::{delete_timestamp_policy, get_timestamp_policies, set_timestamp_policy};
 use tokio::{signal, sync::Mutex};
-use tower_http::{classify::ServerErrorsFailureClass, trace::TraceLayer};
+use tower_http::{
+    classify::ServerErrorsFailureClass, decompression::RequestDecompressionLayer,
+    limit::RequestBodyLimitLayer, trace::TraceLayer,
+};
 use tracing::{error, info, warn, Span};
+use tracks::{import_track_file, import_track_files};
+use transforms::{delete_transform, get_transforms, set_transform, test_transform};
 use uuid::Uuid;
+use webhooks::{add_webhook, delete_webhook, get_webhooks, receive_webhook};
+use writer::{Writer, WriterConfig};
 
+mod aggregate;
 mod auth;
 mod buckets;
+mod csv_import;
 mod data;
 mod emitters;
 mod endpoints;
 mod error;
 mod gps;
+mod influx;
+mod ingest;
+mod limits;
+mod mapping;
 mod migration;
+mod mqtt;
+mod payload_filters;
+mod pollers;
+mod prometheus;
+mod schemas;
 mod spa;
+mod timestamp_policies;
+mod tracks;
+mod transforms;
 mod utils;
+mod webhooks;
+mod writer;
+
+const DEFAULT_MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
 
 #[derive(Clone)]
 struct AppState {
     connection: Arc<Mutex<Connection>>,
     admin_auth: String,
+    // owns all writes to the `timeseries` table
+    writer: Writer,
+    // applies to buckets without a policy of their own
+    timestamp_policy: Option<TimestampPolicy>,
+    rate_limiter: RateLimiter,
+    // peers whose `X-Forwarded-For` header is used as the client address
+    trusted_proxies: Arc<Vec<IpAddr>>,
+    // limit of ingest bodies after decompression
+    max_body_bytes: usize,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State on a migrated in-memory database, for handler tests
+    async fn in_memory() -> Self {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        apply_migrations(conn.clone()).await.unwrap();
+        let writer = Writer::spawn(&*conn.lock().await, WriterConfig::from_env(false)).unwrap();
+
+        AppState {
+            connection: conn,
+            admin_auth: "admin".into(),
+            writer,
+            timestamp_policy: None,
+            rate_limiter: RateLimiter::default(),
+            trusted_proxies: Arc::new(Vec::new()),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        }
+    }
+}
+
+/// Routes emitters upload data to. Request bodies may be compressed with
+/// gzip, deflate or zstd (`Content-Encoding`) and are limited to
+/// `max_body_bytes` and the emitter's own limit after decompression.
+fn ingest_routes(state: AppState, max_body_bytes: usize) -> Router<AppState> {
+    Router::new()
+        .route("/api/data", post(upload_data))
+        .route("/api/data/batch", post(upload_data_batch))
+        .route("/api/data/ndjson", post(upload_data_ndjson))
+        .route("/api/v2/write", post(upload_line_protocol))
+        .route("/api/data/:emitter/:bucket", post(upload_data_url_only))
+        .route("/api/data/:emitter/:bucket/csv", post(import_csv))
+        .route("/api/gps/:emitter/:bucket", post(upload_gps_data))
+        .route("/api/gps/:emitter/:bucket/import", post(import_track_file))
+        .route(
+            "/api/owntracks/:emitter/:bucket",
+            post(upload_owntracks_data),
+        )
+        // The limits replace axum's default one and are applied to the decompressed body
+        .layer(middleware::from_fn_with_state(state, limit_body))
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(max_body_bytes))
+        .layer(RequestDecompressionLayer::new())
 }
 
 #[tokio::main]
@@ -49,32 +149,116 @@ pub async fn main() -> Result<(), AppError> {
         Err(_) => warn!("Failed to load .env file"),
     };
 
+    let conn = Arc::new(Mutex::new(Connection::open("./db/db.duckdb")?));
+
+    info!("Opened database connection");
+
+    apply_migrations(conn.clone()).await?;
+
+    // `observatory import <bucket> <file>...` imports GPX/KML tracks and exits
+    let args: Vec<String> = env::args().collect();
+    if let [_, command, bucket, files @ ..] = args.as_slice() {
+        if command == "import" {
+            let writer = Writer::spawn(&*conn.lock().await, WriterConfig::from_env(false))?;
+            return import_track_files(conn, writer, bucket, files).await;
+        }
+    }
+
     let Some((_, basic_auth)) = env::vars().find(|v| v.0.eq("ADMIN_BASIC_AUTH")) else {
         error!("Admin auth credentials not in environment");
         abort();
     };
     info!("Found ADMIN_BASIC_AUTH in environment");
 
-    let conn = Arc::new(Mutex::new(Connection::open("./db/db.duckdb")?));
+    let dedupe_identical = env::var("DEDUPE_IDENTICAL_POINTS").is_ok_and(|v| v == "true");
+    info!(
+        message = "Deduplication of identical points",
+        enabled = dedupe_identical
+    );
 
-    info!("Opened database connection");
+    let timestamp_policy = TimestampPolicy::from_env();
+    info!(message = "Global timestamp policy", policy = ?timestamp_policy);
 
-    apply_migrations(conn.clone()).await?;
+    let writer = Writer::spawn(
+        &*conn.lock().await,
+        WriterConfig::from_env(dedupe_identical),
+    )?;
+
+    tokio::spawn(run_pollers(conn.clone(), writer.clone()));
+
+    match MqttConfig::from_env() {
+        Some(config) => {
+            tokio::spawn(run_mqtt_subscriber(config, conn.clone(), writer.clone()));
+        }
+        None => info!("MQTT_HOST not in environment, MQTT subscriber disabled"),
+    };
+
+    let max_body_bytes = env::var("MAX_BODY_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES);
+    info!(message = "Maximum ingest body size", bytes = max_body_bytes);
+
+    let trusted_proxies = trusted_proxies_from_env();
+    info!(message = "Trusted proxies", proxies = ?trusted_proxies);
+
+    let state = AppState {
+        connection: conn,
+        admin_auth: basic_auth,
+        writer,
+        timestamp_policy,
+        rate_limiter: RateLimiter::default(),
+        trusted_proxies: Arc::new(trusted_proxies),
+        max_body_bytes,
+    };
 
     let app = Router::new()
-        .route("/api/data", post(upload_data))
-        .route("/api/data/:emitter/:bucket", post(upload_data_url_only))
+        .merge(ingest_routes(state.clone(), max_body_bytes))
+        // remote_write bodies are snappy compressed, which is handled by the route itself
+        .route(
+            "/api/prometheus/write",
+            post(upload_remote_write)
+                .layer(middleware::from_fn_with_state(state.clone(), limit_body)),
+        )
         .route("/api/data", get(get_data))
         .route("/api/data", delete(delete_data))
+        .route("/api/data/aggregate", get(get_aggregated_data))
+        .route("/api/data/latest", get(get_latest_data))
         .route("/api/weight", get(get_weight))
         .route("/api/co2", get(get_co2))
         .route("/api/observatory", get(get_observatory_info))
-        .route("/api/gps/:emitter/:bucket", post(upload_gps_data))
         .route("/api/gps/:bucket", get(get_gps_coords))
         .route("/api/emitter", get(get_emitters))
         .route("/api/emitter", post(add_emitter))
+        .route("/api/emitter", put(update_emitter))
         .route("/api/emitter", delete(delete_emitter))
+        .route("/api/emitter/silent", get(get_silent_emitters))
         .route("/api/buckets", get(get_distinct_buckets))
+        .route("/api/schema", get(get_schemas))
+        .route("/api/schema", post(set_schema))
+        .route("/api/schema", delete(delete_schema))
+        .route("/api/schema/check", post(check_schema))
+        .route("/api/timestamp_policy", get(get_timestamp_policies))
+        .route("/api/timestamp_policy", post(set_timestamp_policy))
+        .route("/api/timestamp_policy", delete(delete_timestamp_policy))
+        .route("/api/transform", get(get_transforms))
+        .route("/api/transform", post(set_transform))
+        .route("/api/transform", delete(delete_transform))
+        .route("/api/transform/test", post(test_transform))
+        .route("/api/webhook", get(get_webhooks))
+        .route("/api/webhook", post(add_webhook))
+        .route("/api/webhook", delete(delete_webhook))
+        .route(
+            "/api/webhook/:name",
+            post(receive_webhook).layer(middleware::from_fn_with_state(state.clone(), limit_body)),
+        )
+        .route(
+            "/api/webhook/:name/:emitter",
+            post(receive_webhook).layer(middleware::from_fn_with_state(state.clone(), limit_body)),
+        )
+        .route("/api/poller", get(get_pollers))
+        .route("/api/poller", post(set_poller))
+        .route("/api/poller", delete(delete_poller))
         .fallback(static_handler)
         .layer(
             TraceLayer::new_for_http()
@@ -96,10 +280,7 @@ pub async fn main() -> Result<(), AppError> {
                     },
                 ),
         )
-        .with_state(AppState {
-            connection: conn,
-            admin_auth: basic_auth,
-        });
+        .with_state(state);
 
     let port = 3000;
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
@@ -110,13 +291,16 @@ pub async fn main() -> Result<(), AppError> {
         })?;
 
     info!(message = "Starting server", port);
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .map_err(|e| {
-            error!(message = "Failed to start server", error=%e);
-            AppError::Status(StatusCode::SERVICE_UNAVAILABLE)
-        })?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .map_err(|e| {
+        error!(message = "Failed to start server", error=%e);
+        AppError::Status(StatusCode::SERVICE_UNAVAILABLE)
+    })?;
 
     Ok(())
 }
@@ -145,3 +329,462 @@ async fn shutdown_signal() {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use axum::http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+    use duckdb::params;
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const TOKEN: &str = "test-token";
+
+    async fn test_app(max_body_bytes: usize) -> (Router, Arc<Mutex<Connection>>) {
+        let state = AppState {
+            max_body_bytes,
+            ..AppState::in_memory().await
+        };
+        let conn = state.connection.clone();
+        conn.lock()
+            .await
+            .execute(
+                "INSERT INTO emitters (token, description) VALUES (?, ?);",
+                params![TOKEN, "test"],
+            )
+            .unwrap();
+
+        let app = ingest_routes(state.clone(), max_body_bytes).with_state(state);
+
+        (app, conn)
+    }
+
+    async fn set_emitter_column(conn: &Arc<Mutex<Connection>>, column: &str, value: i64) {
+        conn.lock()
+            .await
+            .execute(
+                &format!("UPDATE emitters SET {} = ? WHERE description = ?;", column),
+                params![value, "test"],
+            )
+            .unwrap();
+    }
+
+    async fn emitter_column(conn: &Arc<Mutex<Connection>>, column: &str) -> Option<i64> {
+        conn.lock()
+            .await
+            .query_row(
+                &format!("SELECT {} FROM emitters WHERE description = ?;", column),
+                params!["test"],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    fn compress(encoding: &str, body: &[u8]) -> Vec<u8> {
+        match encoding {
+            "gzip" => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+            "deflate" => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+            "zstd" => zstd::encode_all(body, 0).unwrap(),
+            _ => body.to_vec(),
+        }
+    }
+
+    async fn post(app: Router, uri: &str, encoding: Option<&str>, body: &Value) -> StatusCode {
+        let body = serde_json::to_vec(body).unwrap();
+        let mut request = Request::post(uri)
+            .header("emitter", TOKEN)
+            .header(CONTENT_TYPE, "application/json");
+        let body = match encoding {
+            Some(encoding) => {
+                request = request.header(CONTENT_ENCODING, encoding);
+                compress(encoding, &body)
+            }
+            None => body,
+        };
+
+        app.oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    async fn stored_points(conn: &Arc<Mutex<Connection>>) -> i64 {
+        conn.lock()
+            .await
+            .query_row("SELECT count(*) FROM timeseries;", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    async fn post_body(
+        app: Router,
+        uri: &str,
+        content_type: &str,
+        body: &str,
+    ) -> (StatusCode, Value) {
+        let request = Request::post(uri)
+            .header("emitter", TOKEN)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn locations(count: usize) -> Value {
+        let locations: Vec<Value> = (0..count)
+            .map(|i| {
+                json!({
+                    "type": "Feature",
+                    "geometry": {"type": "Point", "coordinates": [13.4, 52.5 + i as f64 / 1000.0]},
+                    "properties": {"timestamp": format!("2024-10-01T12:00:{:02}Z", i % 60)}
+                })
+            })
+            .collect();
+        json!({ "locations": locations })
+    }
+
+    #[tokio::test]
+    async fn upload_data_accepts_compressed_bodies() {
+        for encoding in [None, Some("gzip"), Some("deflate"), Some("zstd")] {
+            let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+            let body = json!({"bucket": "co2", "payload": {"co2": 612}});
+
+            assert_eq!(
+                post(app, "/api/data", encoding, &body).await,
+                StatusCode::OK
+            );
+            assert_eq!(stored_points(&conn).await, 1, "{:?}", encoding);
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_gps_data_accepts_compressed_bodies() {
+        for encoding in [None, Some("gzip"), Some("deflate"), Some("zstd")] {
+            let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+
+            let status = post(app, "/api/gps/test/location", encoding, &locations(3)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(stored_points(&conn).await, 3, "{:?}", encoding);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_encoding_is_rejected() {
+        let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+        let body = json!({"bucket": "co2", "payload": {"co2": 612}});
+
+        let status = post(app, "/api/data", Some("br"), &body).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(stored_points(&conn).await, 0);
+    }
+
+    #[tokio::test]
+    async fn decompressed_size_is_limited() {
+        // Highly repetitive, so the compressed body stays far below the limit
+        let body = locations(5000);
+        let compressed = compress("gzip", &serde_json::to_vec(&body).unwrap());
+        let limit = 64 * 1024;
+        assert!(compressed.len() < limit);
+
+        let (app, conn) = test_app(limit).await;
+        let status = post(app, "/api/gps/test/location", Some("gzip"), &body).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(stored_points(&conn).await, 0);
+
+        let (app, conn) = test_app(limit).await;
+        let body = json!({"bucket": "co2", "payload": {"padding": "0".repeat(limit)}});
+        let status = post(app, "/api/data", Some("zstd"), &body).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(stored_points(&conn).await, 0);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_get_retry_after() {
+        let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+        set_emitter_column(&conn, "requests_per_minute", 2).await;
+        let body = json!({"bucket": "co2", "payload": {"co2": 612}});
+
+        for _ in 0..2 {
+            let status = post(app.clone(), "/api/data", None, &body).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let request = Request::post("/api/data")
+            .header("emitter", TOKEN)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+
+        assert_eq!(stored_points(&conn).await, 2);
+        assert_eq!(emitter_column(&conn, "rate_limited").await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn emitter_body_limit_applies_to_streamed_and_decompressed_bodies() {
+        let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+        set_emitter_column(&conn, "max_body_bytes", 100).await;
+
+        let small = json!({"bucket": "co2", "payload": {"co2": 612}});
+        let status = post(app.clone(), "/api/data", None, &small).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Rejected by its Content-Length before the body is read
+        let large = json!({"bucket": "co2", "payload": {"note": "x".repeat(1000)}});
+        let request = Request::post("/api/data")
+            .header("emitter", TOKEN)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, large.to_string().len())
+            .body(Body::from(large.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Compressed below the limit, but not after decompression
+        assert!(compress("gzip", large.to_string().as_bytes()).len() < 100);
+        let status = post(app.clone(), "/api/data", Some("gzip"), &large).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Chunked, without Content-Length
+        let lines = (0..10).map(move |_| Ok::<_, std::io::Error>(format!("{}\n", small)));
+        let request = Request::post("/api/data/ndjson")
+            .header("emitter", TOKEN)
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from_stream(futures_util::stream::iter(lines)))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        assert_eq!(stored_points(&conn).await, 1);
+        assert_eq!(emitter_column(&conn, "body_too_large").await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn points_over_the_daily_quota_are_rejected() {
+        let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+        set_emitter_column(&conn, "max_points_per_day", 3).await;
+        let batch = |count: usize| {
+            let items: Vec<Value> = (0..count)
+                .map(|i| json!({"bucket": "co2", "payload": {"co2": i}}))
+                .collect();
+            Value::Array(items)
+        };
+
+        // A single batch can't go over the quota
+        let status = post(app.clone(), "/api/data/batch", None, &batch(4)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(stored_points(&conn).await, 0);
+
+        let status = post(app.clone(), "/api/data/batch", None, &batch(3)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let request = Request::post("/api/data/batch")
+            .header("emitter", TOKEN)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(batch(1).to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+
+        assert_eq!(stored_points(&conn).await, 3);
+        assert_eq!(emitter_column(&conn, "quota_exceeded").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn track_import_skips_points_of_imported_files() {
+        let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+        let gpx = r#"<gpx><trk><trkseg>
+            <trkpt lat="47.3769" lon="8.5417"><time>2024-10-01T06:00:00Z</time></trkpt>
+            <trkpt lat="47.3770" lon="8.5420"><time>2024-10-01T06:00:05Z</time></trkpt>
+            <trkpt lat="47.3771" lon="8.5421"><time>2024-10-01T06:00:05Z</time></trkpt>
+            <trkpt lat="47.3772" lon="8.5422"></trkpt>
+        </trkseg></trk></gpx>"#;
+
+        let (status, body) = post_body(
+            app.clone(),
+            "/api/gps/test/track/import",
+            "application/gpx+xml",
+            gpx,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        // Points sharing a timestamp are kept, they have their own position in the file
+        assert_eq!(body, json!({"imported": 3, "duplicates": 0, "skipped": 1}));
+
+        let (status, body) = post_body(
+            app.clone(),
+            "/api/gps/test/track/import",
+            "application/gpx+xml",
+            gpx,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"imported": 0, "duplicates": 3, "skipped": 1}));
+        assert_eq!(stored_points(&conn).await, 3);
+
+        // Another file may have points at the same times
+        let other = gpx.replace("8.5417", "8.6417");
+        let (status, body) = post_body(
+            app,
+            "/api/gps/test/track/import",
+            "application/gpx+xml",
+            &other,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"imported": 3, "duplicates": 0, "skipped": 1}));
+        assert_eq!(stored_points(&conn).await, 6);
+    }
+
+    #[tokio::test]
+    async fn csv_import_dry_run_writes_nothing() {
+        let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+        // Flags every row, corrections are only counted when rows are imported
+        conn.lock()
+            .await
+            .execute(
+                "INSERT INTO timestamp_policies (bucket, action, max_past) VALUES (?, ?, ?);",
+                params!["co2", "flag", 3600],
+            )
+            .unwrap();
+        let mut request = json!({
+            "csv": "time,co2\n2024-10-01T06:00:00Z,612\n2024-10-01T06:05:00Z,high\n2024-10-01T06:10:00Z,640\n",
+            "timestamp": { "column": "time" },
+            "fields": [{ "column": "co2", "type": "number" }],
+            "dry_run": 1,
+        });
+        let uri = "/api/data/test/co2/csv";
+
+        let (status, body) =
+            post_body(app.clone(), uri, "application/json", &request.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "imported": 0,
+                "rejected": 1,
+                "errors": [{ "row": 3, "reason": "Invalid number high" }],
+                "preview": [{ "timestamp": "2024-10-01T06:00:00Z", "bucket": "co2", "payload": { "co2": 612.0 } }],
+            })
+        );
+        assert_eq!(stored_points(&conn).await, 0);
+        assert_eq!(
+            emitter_column(&conn, "timestamp_corrections").await,
+            Some(0)
+        );
+
+        request["dry_run"] = Value::Null;
+        let (status, body) = post_body(app, uri, "application/json", &request.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["imported"], 2);
+        assert_eq!(stored_points(&conn).await, 2);
+        assert_eq!(
+            emitter_column(&conn, "timestamp_corrections").await,
+            Some(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn transforms_run_before_schema_validation() {
+        let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+        {
+            let conn = conn.lock().await;
+            let schema = json!({ "type": "object", "required": ["temperature"] });
+            conn.execute(
+                "INSERT INTO bucket_schemas (bucket, json_schema) VALUES (?, ?);",
+                params!["climate", schema.to_string()],
+            )
+            .unwrap();
+            let rules = json!([{ "type": "rename", "field": "temp", "to": "temperature" }]);
+            conn.execute(
+                "INSERT INTO bucket_transforms (bucket, rules) VALUES (?, ?);",
+                params!["climate", rules.to_string()],
+            )
+            .unwrap();
+        }
+
+        let body = json!({"bucket": "climate", "payload": {"temp": 21.5}});
+        assert_eq!(post(app, "/api/data", None, &body).await, StatusCode::OK);
+
+        let payload: String = conn
+            .lock()
+            .await
+            .query_row("SELECT CAST(payload as Text) FROM timeseries;", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&payload).unwrap(),
+            json!({"temperature": 21.5})
+        );
+    }
+
+    #[tokio::test]
+    async fn ndjson_lines_are_validated_and_checked_against_policies() {
+        let (app, conn) = test_app(DEFAULT_MAX_BODY_BYTES).await;
+        {
+            let conn = conn.lock().await;
+            let schema = json!({ "type": "object", "required": ["temperature"] });
+            conn.execute(
+                "INSERT INTO bucket_schemas (bucket, json_schema) VALUES (?, ?);",
+                params!["climate", schema.to_string()],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO timestamp_policies (bucket, action, max_past) VALUES (?, ?, ?);",
+                params!["climate", "reject", 3600],
+            )
+            .unwrap();
+        }
+
+        let body = [
+            json!({"bucket": "climate", "payload": {"temperature": 21.5}}).to_string(),
+            json!({"bucket": "climate", "payload": {"humidity": 40}}).to_string(),
+            json!({"bucket": "climate", "timestamp": "2020-01-01T00:00:00Z", "payload": {"temperature": 3.0}}).to_string(),
+            "not json".to_string(),
+        ]
+        .join("\n");
+        let (status, response) =
+            post_body(app, "/api/data/ndjson", "application/x-ndjson", &body).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["accepted"], 1);
+        assert_eq!(response["rejected"], 3);
+        let lines: Vec<&Value> = response["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| &error["line"])
+            .collect();
+        assert_eq!(lines, [2, 3, 4]);
+        assert_eq!(stored_points(&conn).await, 1);
+    }
+}
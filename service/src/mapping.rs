@@ -0,0 +1,244 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use serde_json_path::JsonPath;
+
+use crate::{error::AppError, ingest::Point};
+
+/// Where to find data points in a JSON document we don't control, e.g. a
+/// webhook body or a polled API response
+#[derive(Deserialize, Serialize)]
+pub struct JsonMapping {
+    // JSONPath of the timestamp, RFC 3339 or seconds since epoch, receive time if not set
+    timestamp: Option<String>,
+    // payload key to JSONPath, the whole item is stored if empty
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+    // JSONPath of an array whose elements become one point each, the paths
+    // above are then relative to the element
+    split: Option<String>,
+}
+
+impl JsonMapping {
+    /// Reject mappings with invalid JSONPaths
+    pub fn check(&self) -> Result<(), AppError> {
+        let paths = self
+            .timestamp
+            .iter()
+            .chain(self.split.iter())
+            .chain(self.fields.values());
+        for path in paths {
+            parse_path(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Points found in `document`, without idempotency keys
+    pub fn points(&self, document: &Value, bucket: &str) -> Result<Vec<Point>, AppError> {
+        self.items(document)?
+            .into_iter()
+            .map(|item| {
+                Ok(Point {
+                    timestamp: self.timestamp(item)?,
+                    bucket: bucket.into(),
+                    payload: self.payload(item)?,
+                    idempotency_key: None,
+                    original_timestamp: None,
+                })
+            })
+            .collect()
+    }
+
+    fn items<'a>(&self, document: &'a Value) -> Result<Vec<&'a Value>, AppError> {
+        let Some(split) = &self.split else {
+            return Ok(vec![document]);
+        };
+
+        let nodes = parse_path(split)?.query(document).all();
+        // `$.events` and `$.events[*]` select the same items
+        match nodes.as_slice() {
+            [Value::Array(items)] => Ok(items.iter().collect()),
+            _ => Ok(nodes),
+        }
+    }
+
+    fn timestamp(&self, item: &Value) -> Result<String, AppError> {
+        let Some(path) = &self.timestamp else {
+            return Ok(Timestamp::now().to_string());
+        };
+
+        let timestamp = match parse_path(path)?.query(item).first() {
+            Some(Value::String(ts)) => Timestamp::from_str(ts).map_err(AppError::DateInputError)?,
+            Some(Value::Number(seconds)) => {
+                let millis = seconds.as_f64().unwrap_or_default() * 1000.0;
+                Timestamp::from_millisecond(millis as i64).map_err(AppError::DateInputError)?
+            }
+            _ => {
+                return Err(AppError::InputError(format!(
+                    "No timestamp found at {}",
+                    path
+                )))
+            }
+        };
+
+        Ok(timestamp.to_string())
+    }
+
+    fn payload(&self, item: &Value) -> Result<Value, AppError> {
+        if self.fields.is_empty() {
+            return Ok(item.clone());
+        }
+
+        let mut payload = Map::new();
+        for (key, path) in self.fields.iter() {
+            // Fields missing from the document are left out
+            if let Some(value) = parse_path(path)?.query(item).first() {
+                payload.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(Value::Object(payload))
+    }
+}
+
+fn parse_path(path: &str) -> Result<JsonPath, AppError> {
+    JsonPath::parse(path)
+        .map_err(|e| AppError::InputError(format!("Invalid JSONPath {}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn mapping(config: Value) -> JsonMapping {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn points(mapping: &JsonMapping, document: Value) -> Vec<(String, Value)> {
+        mapping
+            .points(&document, "weather")
+            .unwrap()
+            .into_iter()
+            .map(|point| {
+                assert_eq!(point.bucket, "weather");
+                assert_eq!(point.idempotency_key, None);
+                (point.timestamp, point.payload)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stores_the_whole_document_at_receive_time() {
+        let before = Timestamp::now();
+        let document = json!({ "temperature": 21.5, "station": { "id": 7 } });
+
+        let points = points(&mapping(json!({})), document.clone());
+
+        assert_eq!(points.len(), 1);
+        assert!(points[0].0.parse::<Timestamp>().unwrap() >= before);
+        assert_eq!(points[0].1, document);
+    }
+
+    #[test]
+    fn maps_fields_and_timestamp() {
+        let mapping = mapping(json!({
+            "timestamp": "$.observed",
+            "fields": { "temperature": "$.main.temp", "station": "$.station.id", "rain": "$.rain" },
+        }));
+        let document = json!({
+            "observed": "2024-10-01T08:00:00+02:00",
+            "main": { "temp": 21.5, "humidity": 40 },
+            "station": { "id": 7 },
+        });
+
+        assert_eq!(
+            points(&mapping, document),
+            [(
+                "2024-10-01T06:00:00Z".to_string(),
+                json!({ "temperature": 21.5, "station": 7 })
+            )]
+        );
+    }
+
+    #[test]
+    fn reads_epoch_seconds() {
+        let mapping = mapping(json!({ "timestamp": "$.time", "fields": { "v": "$.v" } }));
+
+        let timestamps: Vec<String> = [json!(1727762400), json!(1727762400.25)]
+            .into_iter()
+            .flat_map(|time| points(&mapping, json!({ "time": time, "v": 1 })))
+            .map(|(timestamp, _)| timestamp)
+            .collect();
+        assert_eq!(
+            timestamps,
+            ["2024-10-01T06:00:00Z", "2024-10-01T06:00:00.25Z"]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_and_invalid_timestamps() {
+        let mapping = mapping(json!({ "timestamp": "$.time" }));
+
+        for document in [
+            json!({}),
+            json!({ "time": true }),
+            json!({ "time": "noon" }),
+        ] {
+            assert!(
+                mapping.points(&document, "weather").is_err(),
+                "{}",
+                document
+            );
+        }
+    }
+
+    #[test]
+    fn splits_arrays_into_points() {
+        let document = json!({
+            "readings": [
+                { "time": "2024-10-01T06:00:00Z", "temp": 12.5 },
+                { "time": "2024-10-01T07:00:00Z", "temp": 14 },
+            ],
+        });
+        let expected = [
+            (
+                "2024-10-01T06:00:00Z".to_string(),
+                json!({ "temperature": 12.5 }),
+            ),
+            (
+                "2024-10-01T07:00:00Z".to_string(),
+                json!({ "temperature": 14 }),
+            ),
+        ];
+
+        for split in ["$.readings", "$.readings[*]"] {
+            let mapping = mapping(json!({
+                "split": split,
+                "timestamp": "$.time",
+                "fields": { "temperature": "$.temp" },
+            }));
+            assert_eq!(points(&mapping, document.clone()), expected, "{}", split);
+        }
+
+        let mapping = mapping(json!({ "split": "$.missing" }));
+        assert!(points(&mapping, document).is_empty());
+    }
+
+    #[test]
+    fn checks_paths() {
+        assert!(
+            mapping(json!({ "timestamp": "$.time", "fields": { "v": "$.a[0]" } }))
+                .check()
+                .is_ok()
+        );
+        assert!(mapping(json!({ "split": "readings" })).check().is_err());
+        assert!(mapping(json!({ "fields": { "v": "$.[" } }))
+            .check()
+            .is_err());
+    }
+}
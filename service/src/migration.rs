@@ -25,6 +25,95 @@ pub async fn apply_migrations(conn: Arc<Mutex<Connection>>) -> Result<(), duckdb
         ",
     )?;
 
+    conn.execute_batch(
+        r"ALTER TABLE timeseries ADD COLUMN IF NOT EXISTS idempotency_key TEXT;
+        ",
+    )?;
+
+    conn.execute_batch(
+        r"CREATE TABLE IF NOT EXISTS bucket_schemas (
+            bucket TEXT PRIMARY KEY,
+            json_schema JSON NOT NULL
+          );
+        ",
+    )?;
+
+    conn.execute_batch(
+        r"ALTER TABLE emitters ADD COLUMN IF NOT EXISTS allowed_buckets JSON;
+        ",
+    )?;
+
+    conn.execute_batch(
+        r"ALTER TABLE timeseries ADD COLUMN IF NOT EXISTS original_timestamp TIMESTAMPTZ;
+          ALTER TABLE emitters ADD COLUMN IF NOT EXISTS timestamp_corrections BIGINT DEFAULT 0;
+          CREATE TABLE IF NOT EXISTS timestamp_policies (
+            bucket TEXT PRIMARY KEY,
+            action TEXT NOT NULL,
+            max_past BIGINT,
+            max_future BIGINT
+          );
+        ",
+    )?;
+
+    conn.execute_batch(
+        r"CREATE TABLE IF NOT EXISTS bucket_transforms (
+            bucket TEXT PRIMARY KEY,
+            rules JSON NOT NULL
+          );
+        ",
+    )?;
+
+    conn.execute_batch(
+        r"CREATE TABLE IF NOT EXISTS webhooks (
+            name TEXT PRIMARY KEY,
+            emitter TEXT NOT NULL,
+            config JSON NOT NULL
+          );
+        ",
+    )?;
+
+    conn.execute_batch(
+        r"CREATE TABLE IF NOT EXISTS pollers (
+            name TEXT PRIMARY KEY,
+            config JSON NOT NULL,
+            last_success TIMESTAMPTZ,
+            last_error TEXT,
+            last_error_at TIMESTAMPTZ,
+            failures INTEGER DEFAULT 0
+          );
+        ",
+    )?;
+
+    conn.execute_batch(
+        r"ALTER TABLE emitters ADD COLUMN IF NOT EXISTS last_seen TIMESTAMPTZ;
+          ALTER TABLE emitters ADD COLUMN IF NOT EXISTS last_ip TEXT;
+          CREATE TABLE IF NOT EXISTS emitter_activity (
+            emitter TEXT NOT NULL,
+            day DATE NOT NULL,
+            bucket TEXT NOT NULL,
+            writes BIGINT NOT NULL,
+            PRIMARY KEY (emitter, day, bucket)
+          );
+        ",
+    )?;
+
+    conn.execute_batch(
+        r"ALTER TABLE emitters ADD COLUMN IF NOT EXISTS requests_per_minute BIGINT;
+          ALTER TABLE emitters ADD COLUMN IF NOT EXISTS max_body_bytes BIGINT;
+          ALTER TABLE emitters ADD COLUMN IF NOT EXISTS max_points_per_day BIGINT;
+          ALTER TABLE emitters ADD COLUMN IF NOT EXISTS rate_limited BIGINT DEFAULT 0;
+          ALTER TABLE emitters ADD COLUMN IF NOT EXISTS body_too_large BIGINT DEFAULT 0;
+          ALTER TABLE emitters ADD COLUMN IF NOT EXISTS quota_exceeded BIGINT DEFAULT 0;
+        ",
+    )?;
+
+    // DuckDB only uses single column indexes for lookups, the bucket is
+    // filtered after the key
+    conn.execute_batch(
+        r"CREATE INDEX IF NOT EXISTS timeseries_idempotency_key ON timeseries (idempotency_key);
+        ",
+    )?;
+
     info!(message = "Applied migrations");
 
     Ok(())
@@ -0,0 +1,393 @@
+use std::{env, sync::Arc, time::Duration};
+
+use duckdb::Connection;
+use jiff::Timestamp;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, SubscribeFilter};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{
+    error::AppError,
+    ingest::{bucket_validator, validate_payload, Point},
+    transforms::Transforms,
+    writer::Writer,
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub struct MqttConfig {
+    host: String,
+    port: u16,
+    client_id: String,
+    credentials: Option<(String, String)>,
+    subscriptions: Vec<Subscription>,
+}
+
+struct Subscription {
+    // MQTT topic filter, may contain `+` and `#` wildcards
+    pattern: String,
+    bucket: String,
+}
+
+impl MqttConfig {
+    /// Read the broker configuration from the environment. The subscriber is
+    /// disabled if `MQTT_HOST` is not set. Subscriptions are configured in
+    /// `MQTT_TOPICS` as comma separated `pattern=bucket` pairs, e.g.
+    /// `zigbee2mqtt/living-room=temperature-living-room,tasmota/+/SENSOR=tasmota`
+    pub fn from_env() -> Option<Self> {
+        let host = env::var("MQTT_HOST").ok()?;
+        let port = env::var("MQTT_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(1883);
+        let client_id = env::var("MQTT_CLIENT_ID").unwrap_or("observatory".into());
+        let credentials = env::var("MQTT_USERNAME")
+            .ok()
+            .zip(env::var("MQTT_PASSWORD").ok());
+
+        let subscriptions = parse_subscriptions(&env::var("MQTT_TOPICS").unwrap_or_default());
+
+        if subscriptions.is_empty() {
+            warn!(message = "MQTT_HOST is set but MQTT_TOPICS contains no subscriptions");
+        }
+
+        Some(MqttConfig {
+            host,
+            port,
+            client_id,
+            credentials,
+            subscriptions,
+        })
+    }
+}
+
+/// Parse comma separated `pattern=bucket` pairs, skipping incomplete ones
+fn parse_subscriptions(topics: &str) -> Vec<Subscription> {
+    topics
+        .split(',')
+        .filter_map(|entry| {
+            let (pattern, bucket) = entry.split_once('=')?;
+            Some(Subscription {
+                pattern: pattern.trim().into(),
+                bucket: bucket.trim().into(),
+            })
+        })
+        .filter(|s| !s.pattern.is_empty() && !s.bucket.is_empty())
+        .collect()
+}
+
+/// Connect to the broker and store every matching message in its bucket.
+/// Reconnects with exponential backoff whenever the connection is lost.
+#[tracing::instrument(skip_all, fields( host = %config.host, port = config.port))]
+pub async fn run_mqtt_subscriber(
+    config: MqttConfig,
+    connection: Arc<Mutex<Connection>>,
+    writer: Writer,
+) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some((username, password)) = &config.credentials {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!(message = "Connected to MQTT broker");
+                backoff = INITIAL_BACKOFF;
+
+                // Subscribing to an empty list is a protocol error
+                if config.subscriptions.is_empty() {
+                    continue;
+                }
+
+                // Subscriptions don't survive a clean session, renew them on every connect
+                let filters = config
+                    .subscriptions
+                    .iter()
+                    .map(|s| SubscribeFilter::new(s.pattern.clone(), QoS::AtLeastOnce));
+                if let Err(e) = client.try_subscribe_many(filters) {
+                    error!(message = "Failed to subscribe to MQTT topics", error = %e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let Some(subscription) = config
+                    .subscriptions
+                    .iter()
+                    .find(|s| topic_matches(&s.pattern, &publish.topic))
+                else {
+                    warn!(
+                        message = "No subscription for MQTT topic",
+                        topic = publish.topic
+                    );
+                    continue;
+                };
+
+                // The writer acknowledges once the point is flushed, waiting for it
+                // here would hold up every following message
+                let (connection, writer) = (connection.clone(), writer.clone());
+                let bucket = subscription.bucket.clone();
+                tokio::spawn(async move {
+                    let inserted =
+                        insert_message(&connection, &writer, &bucket, &publish.payload).await;
+                    if let Err(e) = inserted {
+                        error!(message = "Failed to store MQTT message", topic = publish.topic, error = %e);
+                    }
+                });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!(message = "MQTT connection error", error = %e, backoff = ?backoff);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+async fn insert_message(
+    connection: &Arc<Mutex<Connection>>,
+    writer: &Writer,
+    bucket: &str,
+    message: &[u8],
+) -> Result<(), AppError> {
+    let mut point = message_point(bucket, message)?;
+    {
+        let conn = connection.lock().await;
+        Transforms::default().apply(&conn, &mut point)?;
+        let validator = bucket_validator(&conn, bucket)?;
+        validate_payload(validator.as_ref(), &point.payload)?;
+    }
+
+    writer.write(None, vec![point]).await?;
+
+    Ok(())
+}
+
+/// Messages carry a JSON payload and are stored at the time they are received
+fn message_point(bucket: &str, message: &[u8]) -> Result<Point, AppError> {
+    Ok(Point {
+        timestamp: Timestamp::now().to_string(),
+        bucket: bucket.into(),
+        payload: serde_json::from_slice(message)?,
+        idempotency_key: None,
+        original_timestamp: None,
+    })
+}
+
+/// Match a topic against an MQTT topic filter with `+` and `#` wildcards
+fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern = pattern.split('/');
+    let mut topic = topic.split('/');
+
+    loop {
+        match (pattern.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(p), Some(t)) if p == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use rumqttc::{
+        mqttbytes::v4::{self, ConnAck, ConnectReturnCode, Publish, SubAck, SubscribeReasonCode},
+        mqttbytes::Error as PacketError,
+    };
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        migration::apply_migrations,
+        writer::{Writer, WriterConfig},
+    };
+
+    /// Read the next packet the client sends
+    async fn read_packet(stream: &mut TcpStream, buffer: &mut BytesMut) -> v4::Packet {
+        loop {
+            match v4::read(buffer, 1024 * 1024) {
+                Ok(packet) => return packet,
+                Err(PacketError::InsufficientBytes(_)) => {
+                    assert!(stream.read_buf(buffer).await.unwrap() > 0, "client hung up");
+                }
+                Err(e) => panic!("invalid packet: {:?}", e),
+            }
+        }
+    }
+
+    /// Accept one client on a local port and publish `messages` once it
+    /// subscribed, returns the port
+    async fn stub_broker(messages: Vec<(&'static str, Value)>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = BytesMut::new();
+            let mut out = BytesMut::new();
+
+            assert!(matches!(
+                read_packet(&mut stream, &mut buffer).await,
+                v4::Packet::Connect(_)
+            ));
+            ConnAck::new(ConnectReturnCode::Success, false)
+                .write(&mut out)
+                .unwrap();
+            stream.write_all_buf(&mut out).await.unwrap();
+
+            let v4::Packet::Subscribe(subscribe) = read_packet(&mut stream, &mut buffer).await
+            else {
+                panic!("expected a subscription");
+            };
+            let codes = subscribe
+                .filters
+                .iter()
+                .map(|filter| SubscribeReasonCode::Success(filter.qos))
+                .collect();
+            SubAck::new(subscribe.pkid, codes).write(&mut out).unwrap();
+            for (topic, message) in messages {
+                Publish::new(topic, QoS::AtMostOnce, message.to_string())
+                    .write(&mut out)
+                    .unwrap();
+            }
+            stream.write_all_buf(&mut out).await.unwrap();
+
+            // Keep the connection open until the test ends
+            while stream
+                .read_buf(&mut buffer)
+                .await
+                .is_ok_and(|read| read > 0)
+            {}
+        });
+
+        port
+    }
+
+    async fn stored_points(conn: &Arc<Mutex<Connection>>) -> Vec<(String, Value)> {
+        let conn = conn.lock().await;
+        let mut stmt = conn
+            .prepare("SELECT bucket, CAST(payload as Text) FROM timeseries ORDER BY bucket;")
+            .unwrap();
+        let rows: Result<Vec<(String, String)>, _> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect();
+
+        rows.unwrap()
+            .into_iter()
+            .map(|(bucket, payload)| (bucket, serde_json::from_str(&payload).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn matches_exact_topics() {
+        assert!(topic_matches("home/kitchen/temp", "home/kitchen/temp"));
+        assert!(!topic_matches("home/kitchen/temp", "home/kitchen"));
+        assert!(!topic_matches("home/kitchen", "home/kitchen/temp"));
+        assert!(!topic_matches("home/kitchen/temp", "home/garden/temp"));
+    }
+
+    #[test]
+    fn single_level_wildcard_matches_one_level() {
+        assert!(topic_matches("tasmota/+/SENSOR", "tasmota/plug-1/SENSOR"));
+        assert!(topic_matches("+/status", "plug/status"));
+        assert!(!topic_matches("tasmota/+/SENSOR", "tasmota/SENSOR"));
+        assert!(!topic_matches("tasmota/+/SENSOR", "tasmota/a/b/SENSOR"));
+        assert!(!topic_matches("tasmota/+", "tasmota/plug-1/SENSOR"));
+    }
+
+    #[test]
+    fn multi_level_wildcard_matches_remaining_levels() {
+        assert!(topic_matches("zigbee2mqtt/#", "zigbee2mqtt/living-room"));
+        assert!(topic_matches(
+            "zigbee2mqtt/#",
+            "zigbee2mqtt/living-room/availability"
+        ));
+        assert!(topic_matches("zigbee2mqtt/#", "zigbee2mqtt"));
+        assert!(topic_matches("#", "any/topic"));
+        assert!(!topic_matches("zigbee2mqtt/#", "tasmota/plug"));
+    }
+
+    #[test]
+    fn parses_subscriptions() {
+        let subscriptions =
+            parse_subscriptions(" a/+/b = bucket-a ,broken,=no-pattern,no-bucket=,c/# =c");
+
+        let pairs: Vec<(&str, &str)> = subscriptions
+            .iter()
+            .map(|s| (s.pattern.as_str(), s.bucket.as_str()))
+            .collect();
+        assert_eq!(pairs, [("a/+/b", "bucket-a"), ("c/#", "c")]);
+        assert!(parse_subscriptions("").is_empty());
+    }
+
+    #[test]
+    fn maps_json_messages_to_points() {
+        let before = Timestamp::now();
+        let point = message_point("climate", br#"{"temperature": 21.5, "battery": 97}"#).unwrap();
+
+        assert_eq!(point.bucket, "climate");
+        assert_eq!(point.payload, json!({ "temperature": 21.5, "battery": 97 }));
+        assert!(point.timestamp.parse::<Timestamp>().unwrap() >= before);
+        assert_eq!(point.idempotency_key, None);
+    }
+
+    #[test]
+    fn rejects_non_json_messages() {
+        assert!(message_point("climate", b"21.5 degrees").is_err());
+    }
+
+    #[tokio::test]
+    async fn stores_messages_from_the_broker() {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        apply_migrations(conn.clone()).await.unwrap();
+        let writer = Writer::spawn(&*conn.lock().await, WriterConfig::from_env(false)).unwrap();
+
+        let port = stub_broker(vec![
+            ("zigbee2mqtt/living-room", json!({ "temperature": 21.5 })),
+            ("tasmota/plug-1/SENSOR", json!({ "power": 12 })),
+            ("unsubscribed/topic", json!({ "ignored": true })),
+        ])
+        .await;
+        let config = MqttConfig {
+            host: "127.0.0.1".into(),
+            port,
+            client_id: "observatory-test".into(),
+            credentials: None,
+            subscriptions: parse_subscriptions(
+                "zigbee2mqtt/living-room=living-room,tasmota/+/SENSOR=tasmota",
+            ),
+        };
+        let subscriber = tokio::spawn(run_mqtt_subscriber(config, conn.clone(), writer));
+
+        let mut points = Vec::new();
+        for _ in 0..100 {
+            points = stored_points(&conn).await;
+            if points.len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        subscriber.abort();
+
+        assert_eq!(
+            points,
+            [
+                ("living-room".to_string(), json!({ "temperature": 21.5 })),
+                ("tasmota".to_string(), json!({ "power": 12 })),
+            ]
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use duckdb::ToSql;
+use serde_json::Value;
+
+use crate::error::AppError;
+
+/// Condition on a payload field, e.g. `$.co2 > 1000`, `$.motion contains "walking"`
+/// or `$.name == "Heuried"`
+#[derive(Debug)]
+pub struct PayloadFilter {
+    // JSON path into the payload
+    path: String,
+    operator: Operator,
+    value: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    // element of an array or substring of a string
+    Contains,
+}
+
+impl PayloadFilter {
+    /// DuckDB condition for the filter. Path and value are never part of the
+    /// SQL, they are pushed to `values` for its placeholders.
+    pub fn sql(&self, values: &mut Vec<Box<dyn ToSql>>) -> Result<&'static str, AppError> {
+        let path = || Box::new(self.path.clone());
+
+        let condition = match (self.operator, &self.value) {
+            (Operator::Contains, Value::String(_) | Value::Number(_)) => {
+                let text = match &self.value {
+                    Value::String(text) => text.clone(),
+                    value => value.to_string(),
+                };
+                values.push(path());
+                values.push(path());
+                values.push(Box::new(self.value.to_string()));
+                values.push(path());
+                values.push(Box::new(text));
+                "CASE json_type(payload, (?)) WHEN 'ARRAY' THEN json_contains(json_extract(payload, (?)), (?)) WHEN 'VARCHAR' THEN contains(json_extract_string(payload, (?)), (?)) ELSE false END"
+            }
+            (operator, Value::Number(number)) => {
+                values.push(path());
+                values.push(Box::new(number.as_f64()));
+                match operator {
+                    Operator::Eq => "TRY_CAST(json_extract(payload, (?)) as DOUBLE) = (?)",
+                    Operator::Ne => "TRY_CAST(json_extract(payload, (?)) as DOUBLE) != (?)",
+                    Operator::Gt => "TRY_CAST(json_extract(payload, (?)) as DOUBLE) > (?)",
+                    Operator::Ge => "TRY_CAST(json_extract(payload, (?)) as DOUBLE) >= (?)",
+                    Operator::Lt => "TRY_CAST(json_extract(payload, (?)) as DOUBLE) < (?)",
+                    Operator::Le => "TRY_CAST(json_extract(payload, (?)) as DOUBLE) <= (?)",
+                    Operator::Contains => unreachable!("contains is handled above"),
+                }
+            }
+            (Operator::Eq | Operator::Ne, Value::String(text)) => {
+                values.push(path());
+                values.push(path());
+                values.push(Box::new(text.clone()));
+                // Only string fields match, `json_extract_string` would also render numbers
+                if self.operator == Operator::Eq {
+                    "(json_type(payload, (?)) = 'VARCHAR' AND json_extract_string(payload, (?)) = (?))"
+                } else {
+                    "(json_type(payload, (?)) = 'VARCHAR' AND json_extract_string(payload, (?)) != (?))"
+                }
+            }
+            (Operator::Eq | Operator::Ne, Value::Bool(_) | Value::Null) => {
+                values.push(path());
+                values.push(Box::new(self.value.to_string()));
+                if self.operator == Operator::Eq {
+                    "CAST(json_extract(payload, (?)) as Text) = (?)"
+                } else {
+                    "CAST(json_extract(payload, (?)) as Text) != (?)"
+                }
+            }
+            _ => {
+                return Err(AppError::InputError(format!(
+                    "Unsupported comparison {:?} with {} for {}",
+                    self.operator, self.value, self.path
+                )))
+            }
+        };
+
+        Ok(condition)
+    }
+}
+
+/// Parse conditions joined by `and`, e.g. `$.co2 > 1000 and $.room == "office"`
+pub fn parse_filters(input: &str) -> Result<Vec<PayloadFilter>, AppError> {
+    let mut tokens = Tokens { input, position: 0 };
+    let mut filters = Vec::new();
+
+    loop {
+        let path = tokens.word();
+        if !path.starts_with('$') {
+            return Err(AppError::InputError(format!(
+                "Expected JSON path like $.co2, found '{}'",
+                path
+            )));
+        }
+
+        let operator = match tokens.word() {
+            "==" => Operator::Eq,
+            "!=" => Operator::Ne,
+            ">" => Operator::Gt,
+            ">=" => Operator::Ge,
+            "<" => Operator::Lt,
+            "<=" => Operator::Le,
+            "contains" => Operator::Contains,
+            operator => {
+                return Err(AppError::InputError(format!(
+                    "Unknown operator '{}'",
+                    operator
+                )))
+            }
+        };
+
+        let value = tokens.literal()?;
+        filters.push(PayloadFilter {
+            path: path.into(),
+            operator,
+            value,
+        });
+
+        match tokens.word() {
+            "" => return Ok(filters),
+            word if word.eq_ignore_ascii_case("and") => continue,
+            word => {
+                return Err(AppError::InputError(format!(
+                    "Expected 'and', found '{}'",
+                    word
+                )))
+            }
+        }
+    }
+}
+
+struct Tokens<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Next whitespace separated word, empty at the end of the input
+    fn word(&mut self) -> &'a str {
+        self.skip_whitespace();
+        let rest = &self.input[self.position..];
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        self.position += end;
+
+        &rest[..end]
+    }
+
+    /// JSON string, number, boolean or null. Strings may contain whitespace.
+    fn literal(&mut self) -> Result<Value, AppError> {
+        self.skip_whitespace();
+        let rest = &self.input[self.position..];
+
+        let end = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut escaped = false;
+            let closing = quoted.find(|c| {
+                let closes = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                closes
+            });
+            match closing {
+                Some(closing) => closing + 2,
+                None => return Err(AppError::InputError("Unterminated string".into())),
+            }
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
+        self.position += end;
+
+        serde_json::from_str(&rest[..end])
+            .map_err(|_| AppError::InputError(format!("Invalid value '{}'", &rest[..end])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn filter(input: &str) -> PayloadFilter {
+        let mut filters = parse_filters(input).unwrap();
+        assert_eq!(filters.len(), 1, "{}", input);
+        filters.remove(0)
+    }
+
+    #[test]
+    fn parses_conditions_joined_by_and() {
+        let filters =
+            parse_filters(r#"$.co2 > 1000 and $.room == "office"  AND $.on != true"#).unwrap();
+
+        let parsed: Vec<_> = filters
+            .iter()
+            .map(|filter| (filter.path.as_str(), filter.operator, filter.value.clone()))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                ("$.co2", Operator::Gt, json!(1000)),
+                ("$.room", Operator::Eq, json!("office")),
+                ("$.on", Operator::Ne, json!(true)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_all_operators() {
+        for (operator, expected) in [
+            ("==", Operator::Eq),
+            ("!=", Operator::Ne),
+            (">", Operator::Gt),
+            (">=", Operator::Ge),
+            ("<", Operator::Lt),
+            ("<=", Operator::Le),
+            ("contains", Operator::Contains),
+        ] {
+            let filter = filter(&format!("$.value {} 1.5", operator));
+            assert_eq!(filter.operator, expected);
+            assert_eq!(filter.value, json!(1.5));
+        }
+    }
+
+    #[test]
+    fn strings_may_contain_whitespace_and_escaped_quotes() {
+        let filter = filter(r#"$.note == "say \"hi\" and bye""#);
+        assert_eq!(filter.value, json!("say \"hi\" and bye"));
+
+        // An escaped backslash doesn't escape the closing quote
+        let filters = parse_filters(r#"$.dir == "C:\\" and $.n == 1"#).unwrap();
+        assert_eq!(filters[0].value, json!("C:\\"));
+        assert_eq!(filters[1].value, json!(1));
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        for input in [
+            "",
+            "co2 > 1000",
+            "$.co2",
+            "$.co2 ~ 1000",
+            "$.co2 =< 1000",
+            "$.co2 >",
+            "$.co2 > high",
+            r#"$.name == "office"#,
+            r#"$.name == "office\""#,
+            "$.co2 > 1000 or $.co2 < 400",
+            "$.co2 > 1000 and",
+            "$.co2 > 1000 and and $.co2 < 400",
+        ] {
+            assert!(parse_filters(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_combinations() {
+        for input in [
+            r#"$.room > "office""#,
+            "$.on <= true",
+            "$.value >= null",
+            "$.tags contains true",
+            "$.tags contains null",
+            "$.tags contains [1]",
+            "$.value == {}",
+        ] {
+            let filters = parse_filters(input).unwrap();
+            let mut values = Vec::new();
+            assert!(filters[0].sql(&mut values).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn placeholders_match_pushed_values() {
+        for input in [
+            r#"$.motion contains "walking""#,
+            "$.readings contains 3",
+            "$.co2 == 1000",
+            "$.co2 != 1000",
+            "$.co2 > 1000",
+            "$.co2 >= 1000",
+            "$.co2 < 1000",
+            "$.co2 <= 1000",
+            r#"$.room == "office""#,
+            r#"$.room != "office""#,
+            "$.on == true",
+            "$.on != false",
+            "$.error == null",
+        ] {
+            let mut values = Vec::new();
+            let sql = filter(input).sql(&mut values).unwrap();
+            assert_eq!(sql.matches("(?)").count(), values.len(), "{}", input);
+        }
+    }
+}
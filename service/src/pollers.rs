@@ -0,0 +1,496 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use duckdb::{params, Connection};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{sync::Mutex, task::JoinSet, time::Instant};
+use tracing::{error, info, warn};
+
+use crate::{
+    auth::AuthenticatedUser,
+    error::AppError,
+    ingest::{bucket_validator, validate_payload, InsertOutcome, Point},
+    mapping::JsonMapping,
+    transforms::Transforms,
+    writer::Writer,
+    AppState,
+};
+
+// How often the scheduler picks up configuration changes and due pollers
+const SCHEDULER_TICK: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize, Serialize)]
+pub struct PollerConfig {
+    name: String,
+    url: String,
+    // seconds between successful polls
+    interval: u64,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    bucket: String,
+    #[serde(flatten)]
+    mapping: JsonMapping,
+}
+
+struct Schedule {
+    next: Instant,
+    // consecutive failed polls, stretches the interval exponentially
+    failures: u32,
+    running: bool,
+}
+
+/// Poll all configured URLs on their interval and store the mapped responses.
+/// Failing pollers back off exponentially up to an hour.
+pub async fn run_pollers(connection: Arc<Mutex<Connection>>, writer: Writer) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            error!(message = "Failed to create HTTP client, pollers disabled", error = %e);
+            return;
+        }
+    };
+
+    let mut schedules: HashMap<String, Schedule> = HashMap::new();
+    let mut polls = JoinSet::new();
+    let mut ticker = tokio::time::interval(SCHEDULER_TICK);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let configs = match poller_configs(&connection).await {
+                    Ok(configs) => configs,
+                    Err(e) => {
+                        error!(message = "Failed to load pollers", error = %e);
+                        continue;
+                    }
+                };
+
+                // Forget removed pollers, a poll still running finishes on its own
+                schedules.retain(|name, _| configs.iter().any(|config| &config.name == name));
+
+                let now = Instant::now();
+                for config in configs {
+                    let schedule = schedules.entry(config.name.clone()).or_insert(Schedule {
+                        next: now,
+                        failures: 0,
+                        running: false,
+                    });
+                    if schedule.running || schedule.next > now {
+                        continue;
+                    }
+
+                    schedule.running = true;
+                    let (client, connection, writer) =
+                        (client.clone(), connection.clone(), writer.clone());
+                    polls.spawn(async move {
+                        let result = poll(&client, &connection, &writer, &config).await;
+                        (config, result)
+                    });
+                }
+            }
+            Some(finished) = polls.join_next() => {
+                let (config, result) = match finished {
+                    Ok(finished) => finished,
+                    Err(e) => {
+                        error!(message = "Poller panicked", error = %e);
+                        continue;
+                    }
+                };
+
+                if let Err(e) = record_status(&connection, &config.name, &result).await {
+                    error!(message = "Failed to store poller status", poller = %config.name, error = %e);
+                }
+
+                let Some(schedule) = schedules.get_mut(&config.name) else {
+                    continue;
+                };
+                schedule.running = false;
+
+                let interval = Duration::from_secs(config.interval.max(1));
+                match result {
+                    Ok(points) => {
+                        info!(message = "Polled", poller = %config.name, points);
+                        schedule.failures = 0;
+                        schedule.next = Instant::now() + interval;
+                    }
+                    Err(e) => {
+                        schedule.failures += 1;
+                        let backoff = interval
+                            .saturating_mul(2u32.saturating_pow(schedule.failures))
+                            .min(MAX_BACKOFF);
+                        warn!(message = "Poll failed", poller = %config.name, error = %e, backoff = ?backoff);
+                        schedule.next = Instant::now() + backoff;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Fetch the URL once and write the mapped points, returns the number of
+/// points that weren't stored by an earlier poll
+async fn poll(
+    client: &reqwest::Client,
+    connection: &Arc<Mutex<Connection>>,
+    writer: &Writer,
+    config: &PollerConfig,
+) -> Result<usize, String> {
+    let mut request = client.get(&config.url);
+    for (name, value) in config.headers.iter() {
+        request = request.header(name, value);
+    }
+
+    let response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let body = response.bytes().await.map_err(|e| e.to_string())?;
+    let document: Value =
+        serde_json::from_slice(&body).map_err(|e| format!("Invalid JSON response: {}", e))?;
+
+    let mut points = config
+        .mapping
+        .points(&document, &config.bucket)
+        .map_err(|e| e.to_string())?;
+    set_idempotency_keys(&config.name, &mut points);
+
+    {
+        let conn = connection.lock().await;
+        let validator = bucket_validator(&conn, &config.bucket).map_err(|e| e.to_string())?;
+        let mut transforms = Transforms::default();
+        for point in points.iter_mut() {
+            transforms.apply(&conn, point).map_err(|e| e.to_string())?;
+            validate_payload(validator.as_ref(), &point.payload).map_err(|e| e.to_string())?;
+        }
+    }
+
+    let outcomes = writer
+        .write(None, points)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(outcomes
+        .iter()
+        .filter(|outcome| **outcome == InsertOutcome::Inserted)
+        .count())
+}
+
+/// Key points by poller and timestamp, so data that is still in the response
+/// on the next poll isn't stored again. Points sharing a timestamp are told
+/// apart by their order in the response.
+fn set_idempotency_keys(poller: &str, points: &mut [Point]) {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for point in points.iter_mut() {
+        let count = seen.entry(point.timestamp.clone()).or_default();
+        point.idempotency_key = Some(match *count {
+            0 => format!("poller:{}:{}", poller, point.timestamp),
+            n => format!("poller:{}:{}:{}", poller, point.timestamp, n),
+        });
+        *count += 1;
+    }
+}
+
+async fn poller_configs(
+    connection: &Arc<Mutex<Connection>>,
+) -> Result<Vec<PollerConfig>, AppError> {
+    let conn = connection.lock().await;
+
+    let mut stmt = conn.prepare_cached("SELECT CAST(config as Text) FROM pollers;")?;
+    let configs: Result<Vec<String>, _> = stmt.query_map([], |row| row.get(0))?.collect();
+
+    configs?
+        .iter()
+        .map(|config| Ok(serde_json::from_str(config)?))
+        .collect()
+}
+
+async fn record_status(
+    connection: &Arc<Mutex<Connection>>,
+    name: &str,
+    result: &Result<usize, String>,
+) -> Result<(), AppError> {
+    let conn = connection.lock().await;
+    let now = Timestamp::now().to_string();
+
+    match result {
+        Ok(_) => conn.execute(
+            "UPDATE pollers SET last_success = CAST((?) as TIMESTAMPTZ), failures = 0 WHERE name = (?);",
+            params![now, name],
+        )?,
+        Err(e) => conn.execute(
+            "UPDATE pollers SET last_error = (?), last_error_at = CAST((?) as TIMESTAMPTZ), failures = coalesce(failures, 0) + 1 WHERE name = (?);",
+            params![e, now, name],
+        )?,
+    };
+
+    Ok(())
+}
+
+/// Configured pollers with the outcome of their recent polls
+#[tracing::instrument(skip_all)]
+pub async fn get_pollers(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+) -> Result<Json<Vec<PollerResponse>>, AppError> {
+    let conn = state.connection.lock().await;
+
+    let mut stmt = conn.prepare(
+        "SELECT CAST(config as Text), cast(last_success as Text), last_error, cast(last_error_at as Text), coalesce(failures, 0) FROM pollers ORDER BY name;",
+    )?;
+    let response: Result<Vec<(String, PollerStatus)>, _> = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                PollerStatus {
+                    last_success: row.get(1)?,
+                    last_error: row.get(2)?,
+                    last_error_at: row.get(3)?,
+                    failures: row.get(4)?,
+                },
+            ))
+        })?
+        .collect();
+
+    let response = response?
+        .into_iter()
+        .map(|(config, mut status)| {
+            // Format dates in DB like `get_data` does
+            for ts in [&mut status.last_success, &mut status.last_error_at] {
+                if let Some(value) = ts.as_mut() {
+                    *value = value.parse::<Timestamp>()?.to_string();
+                }
+            }
+
+            Ok(PollerResponse {
+                config: serde_json::from_str(&config)?,
+                status,
+            })
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    Ok(Json(response))
+}
+
+/// Create or update a poller. Its status is kept across updates.
+#[tracing::instrument(skip_all, fields( poller = %request.name))]
+pub async fn set_poller(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<PollerConfig>,
+) -> Result<StatusCode, AppError> {
+    request.mapping.check()?;
+    reqwest::Url::parse(&request.url)
+        .map_err(|e| AppError::InputError(format!("Invalid URL {}: {}", request.url, e)))?;
+    if request.interval == 0 {
+        return Err(AppError::InputError("Interval must be positive".into()));
+    }
+
+    let conn = state.connection.lock().await;
+    conn.execute(
+        "INSERT INTO pollers (name, config) VALUES (?, ?) ON CONFLICT (name) DO UPDATE SET config = excluded.config;",
+        params![request.name, serde_json::to_string(&request)?],
+    )?;
+
+    Ok(StatusCode::OK)
+}
+
+#[tracing::instrument(skip_all, fields( poller = %request.name))]
+pub async fn delete_poller(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<DeletePollerRequest>,
+) -> Result<StatusCode, AppError> {
+    let conn = state.connection.lock().await;
+
+    let affected_rows = conn.execute(
+        "DELETE FROM pollers WHERE name = (?);",
+        params![request.name],
+    )?;
+
+    info!(message = "Deleted rows", affected_rows);
+
+    if affected_rows == 0 {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::OK)
+    }
+}
+
+#[derive(Serialize)]
+pub struct PollerResponse {
+    #[serde(flatten)]
+    config: PollerConfig,
+    status: PollerStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PollerStatus {
+    last_success: Option<String>,
+    last_error: Option<String>,
+    last_error_at: Option<String>,
+    // failed polls since the last success
+    failures: i64,
+}
+
+#[derive(Deserialize)]
+pub struct DeletePollerRequest {
+    name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        migration::apply_migrations,
+        writer::{Writer, WriterConfig},
+    };
+
+    /// Serve `body` with `status` on a local port, returns the URL
+    async fn stub_server(status: StatusCode, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route("/readings", get(move || async move { (status, body) }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{}/readings", address)
+    }
+
+    async fn test_database() -> (Arc<Mutex<Connection>>, Writer) {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        apply_migrations(conn.clone()).await.unwrap();
+        let writer = Writer::spawn(&*conn.lock().await, WriterConfig::from_env(false)).unwrap();
+
+        (conn, writer)
+    }
+
+    fn config(url: String) -> PollerConfig {
+        serde_json::from_value(json!({
+            "name": "weather",
+            "url": url,
+            "interval": 60,
+            "bucket": "weather",
+            "split": "$.readings",
+            "timestamp": "$.time",
+            "fields": { "temperature": "$.temp" },
+        }))
+        .unwrap()
+    }
+
+    async fn stored_points(conn: &Arc<Mutex<Connection>>) -> Vec<(String, Value)> {
+        let conn = conn.lock().await;
+        let mut stmt = conn
+            .prepare("SELECT idempotency_key, CAST(payload as Text) FROM timeseries ORDER BY idempotency_key;")
+            .unwrap();
+        let rows: Result<Vec<(String, String)>, _> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect();
+
+        rows.unwrap()
+            .into_iter()
+            .map(|(key, payload)| (key, serde_json::from_str(&payload).unwrap()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn repeated_polls_store_points_once() {
+        let url = stub_server(
+            StatusCode::OK,
+            r#"{"readings": [
+                {"time": "2024-10-01T06:00:00Z", "temp": 12.5},
+                {"time": "2024-10-01T06:10:00Z", "temp": 13.0},
+                {"time": "2024-10-01T06:10:00Z", "temp": 13.5}
+            ]}"#,
+        )
+        .await;
+        let (conn, writer) = test_database().await;
+        let (client, config) = (reqwest::Client::new(), config(url));
+
+        assert_eq!(poll(&client, &conn, &writer, &config).await, Ok(3));
+        assert_eq!(poll(&client, &conn, &writer, &config).await, Ok(0));
+
+        assert_eq!(
+            stored_points(&conn).await,
+            [
+                (
+                    "poller:weather:2024-10-01T06:00:00Z".into(),
+                    json!({ "temperature": 12.5 })
+                ),
+                (
+                    "poller:weather:2024-10-01T06:10:00Z".into(),
+                    json!({ "temperature": 13.0 })
+                ),
+                (
+                    "poller:weather:2024-10-01T06:10:00Z:1".into(),
+                    json!({ "temperature": 13.5 })
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_reported() {
+        let (conn, writer) = test_database().await;
+        let client = reqwest::Client::new();
+
+        let url = stub_server(StatusCode::SERVICE_UNAVAILABLE, "maintenance").await;
+        let error = poll(&client, &conn, &writer, &config(url))
+            .await
+            .unwrap_err();
+        assert!(error.contains("503"), "{}", error);
+
+        let url = stub_server(StatusCode::OK, "<html></html>").await;
+        let error = poll(&client, &conn, &writer, &config(url))
+            .await
+            .unwrap_err();
+        assert!(error.starts_with("Invalid JSON response"), "{}", error);
+
+        let url = stub_server(StatusCode::OK, r#"{"readings": [{"temp": 12.5}]}"#).await;
+        let error = poll(&client, &conn, &writer, &config(url))
+            .await
+            .unwrap_err();
+        assert!(error.contains("No timestamp found at $.time"), "{}", error);
+
+        assert!(stored_points(&conn).await.is_empty());
+    }
+
+    #[test]
+    fn keys_points_sharing_a_timestamp_by_order() {
+        let point = |timestamp: &str| Point {
+            timestamp: timestamp.into(),
+            bucket: "weather".into(),
+            payload: json!({}),
+            idempotency_key: None,
+            original_timestamp: None,
+        };
+        let mut points = vec![point("a"), point("b"), point("a"), point("a")];
+
+        set_idempotency_keys("weather", &mut points);
+        let keys: Vec<&str> = points
+            .iter()
+            .map(|point| point.idempotency_key.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "poller:weather:a",
+                "poller:weather:b",
+                "poller:weather:a:1",
+                "poller:weather:a:2"
+            ]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use axum::{body::Bytes, extract::State, http::StatusCode};
+use jiff::Timestamp;
+use jsonschema::Validator;
+use prost::Message;
+use serde_json::{Map, Number, Value};
+use tracing::info;
+
+use crate::{
+    auth::AuthenticatedEmitter,
+    error::AppError,
+    ingest::{bucket_validator, validate_payload, Point},
+    transforms::Transforms,
+    AppState,
+};
+
+/// Receives Prometheus remote_write requests. Every series is stored in the
+/// bucket named by its `__name__` label, the remaining labels and the sample
+/// value make up the payload.
+#[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
+pub async fn upload_remote_write(
+    State(state): State<AppState>,
+    emitter: AuthenticatedEmitter,
+    body: Bytes,
+) -> Result<StatusCode, AppError> {
+    // The route is outside the decompression layer, so the snappy body is
+    // limited here by the length it declares
+    let max_bytes = match emitter.limits.max_body_bytes {
+        Some(max) => max.max(0) as usize,
+        None => state.max_body_bytes,
+    };
+    let request = decode_write_request(&body, max_bytes)?;
+
+    let mut points = Vec::new();
+    for series in request.timeseries {
+        let (bucket, series_points) = series_points(series)?;
+        emitter.authorize_bucket(&bucket)?;
+        points.extend(series_points);
+    }
+
+    let conn = state.connection.lock().await;
+    let mut transforms = Transforms::default();
+    let mut validators: HashMap<String, Option<Validator>> = HashMap::new();
+    for point in points.iter_mut() {
+        transforms.apply(&conn, point)?;
+        if !validators.contains_key(&point.bucket) {
+            let validator = bucket_validator(&conn, &point.bucket)?;
+            validators.insert(point.bucket.clone(), validator);
+        }
+        validate_payload(validators[&point.bucket].as_ref(), &point.payload)?;
+    }
+    drop(conn);
+
+    let samples = state
+        .writer
+        .write(Some(&emitter.description), points)
+        .await?
+        .len();
+
+    info!(message = "Wrote remote write samples", samples);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Decode a snappy compressed protobuf `WriteRequest` of at most `max_bytes`
+/// decompressed
+fn decode_write_request(body: &[u8], max_bytes: usize) -> Result<WriteRequest, AppError> {
+    let length = snap::raw::decompress_len(body)
+        .map_err(|e| AppError::InputError(format!("Invalid snappy body: {}", e)))?;
+    if length > max_bytes {
+        return Err(AppError::PayloadTooLarge(max_bytes as u64));
+    }
+
+    let decompressed = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| AppError::InputError(format!("Invalid snappy body: {}", e)))?;
+
+    WriteRequest::decode(decompressed.as_slice())
+        .map_err(|e| AppError::InputError(format!("Invalid protobuf body: {}", e)))
+}
+
+/// Bucket of the series and one point per sample
+fn series_points(series: TimeSeries) -> Result<(String, Vec<Point>), AppError> {
+    let mut bucket = None;
+    let mut labels = Map::new();
+    for label in series.labels {
+        if label.name == "__name__" {
+            bucket = Some(label.value);
+        } else {
+            labels.insert(label.name, Value::String(label.value));
+        }
+    }
+
+    let Some(bucket) = bucket else {
+        return Err(AppError::InputError("Series without __name__ label".into()));
+    };
+
+    let mut points = Vec::new();
+    for sample in series.samples {
+        // NaN is used as staleness marker and can't be represented in JSON
+        let Some(value) = Number::from_f64(sample.value) else {
+            continue;
+        };
+
+        let timestamp = Timestamp::from_millisecond(sample.timestamp)
+            .map_err(AppError::DateInputError)?
+            .to_string();
+        let mut payload = labels.clone();
+        payload.insert("value".into(), Value::Number(value));
+
+        points.push(Point {
+            timestamp,
+            bucket: bucket.clone(),
+            payload: Value::Object(payload),
+            idempotency_key: None,
+            original_timestamp: None,
+        });
+    }
+
+    Ok((bucket, points))
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    // milliseconds since epoch
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    fn series(labels: Vec<Label>, samples: Vec<(f64, i64)>) -> TimeSeries {
+        TimeSeries {
+            labels,
+            samples: samples
+                .into_iter()
+                .map(|(value, timestamp)| Sample { value, timestamp })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn decodes_snappy_protobuf_body() {
+        let request = WriteRequest {
+            timeseries: vec![series(
+                vec![label("__name__", "up"), label("job", "node")],
+                vec![(1.0, 1_700_000_000_000)],
+            )],
+        };
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+
+        assert_eq!(decode_write_request(&body, 1024).unwrap(), request);
+    }
+
+    #[test]
+    fn rejects_bodies_decompressing_beyond_the_limit() {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&vec![0; 4096])
+            .unwrap();
+        assert!(body.len() < 1024);
+
+        let result = decode_write_request(&body, 1024);
+        assert!(matches!(result, Err(AppError::PayloadTooLarge(1024))));
+
+        // A body of just its declared length, 1 GiB, is rejected before decompressing
+        let mut declared = Vec::new();
+        let mut length: u64 = 1 << 30;
+        while length >= 0x80 {
+            declared.push((length as u8) | 0x80);
+            length >>= 7;
+        }
+        declared.push(length as u8);
+        let result = decode_write_request(&declared, 1024);
+        assert!(matches!(result, Err(AppError::PayloadTooLarge(1024))));
+    }
+
+    #[test]
+    fn rejects_invalid_bodies() {
+        assert!(decode_write_request(b"not snappy", 1024).is_err());
+
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&[0xff, 0xff, 0xff])
+            .unwrap();
+        assert!(decode_write_request(&body, 1024).is_err());
+    }
+
+    #[test]
+    fn maps_labels_and_samples_to_points() {
+        let (bucket, points) = series_points(series(
+            vec![
+                label("instance", "localhost:9100"),
+                label("__name__", "node_load1"),
+                label("job", "node"),
+            ],
+            vec![(0.5, 1_700_000_000_000), (0.75, 1_700_000_015_500)],
+        ))
+        .unwrap();
+
+        assert_eq!(bucket, "node_load1");
+        assert_eq!(points.len(), 2);
+        assert!(points.iter().all(|point| point.bucket == "node_load1"));
+        assert_eq!(points[0].timestamp, "2023-11-14T22:13:20Z");
+        assert_eq!(points[1].timestamp, "2023-11-14T22:13:35.5Z");
+        assert_eq!(
+            points[1].payload,
+            json!({ "instance": "localhost:9100", "job": "node", "value": 0.75 })
+        );
+    }
+
+    #[test]
+    fn skips_staleness_markers() {
+        let (_, points) = series_points(series(
+            vec![label("__name__", "up")],
+            vec![(f64::NAN, 1_700_000_000_000), (1.0, 1_700_000_015_000)],
+        ))
+        .unwrap();
+
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].payload, json!({ "value": 1.0 }));
+    }
+
+    #[test]
+    fn rejects_series_without_name() {
+        let result = series_points(series(vec![label("job", "node")], vec![(1.0, 0)]));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use axum::{extract::State, http::StatusCode, Json};
+use duckdb::params;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use crate::{
+    auth::AuthenticatedUser,
+    error::AppError,
+    ingest::{bucket_validator, schema_errors},
+    AppState,
+};
+
+// Upper bound of failing data points listed in a schema check
+const MAX_REPORTED_FAILURES: usize = 100;
+
+#[tracing::instrument(skip_all)]
+pub async fn get_schemas(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+) -> Result<Json<Vec<BucketSchema>>, AppError> {
+    let conn = state.connection.lock().await;
+
+    let mut stmt = conn.prepare("SELECT bucket, CAST(json_schema as Text) FROM bucket_schemas;")?;
+    let response: Result<Vec<(String, String)>, _> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect();
+
+    let response = response?
+        .into_iter()
+        .map(|(bucket, schema)| {
+            Ok(BucketSchema {
+                bucket,
+                schema: serde_json::from_str(&schema)?,
+            })
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    Ok(Json(response))
+}
+
+#[tracing::instrument(skip_all, fields( bucket = %request.bucket))]
+pub async fn set_schema(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<BucketSchema>,
+) -> Result<StatusCode, AppError> {
+    jsonschema::validator_for(&request.schema)
+        .map_err(|e| AppError::InputError(format!("Invalid schema: {}", e)))?;
+
+    let conn = state.connection.lock().await;
+    conn.execute(
+        "INSERT OR REPLACE INTO bucket_schemas (bucket, json_schema) VALUES (?, ?);",
+        params![request.bucket, serde_json::to_string(&request.schema)?],
+    )?;
+
+    Ok(StatusCode::OK)
+}
+
+#[tracing::instrument(skip_all, fields( bucket = %request.bucket))]
+pub async fn delete_schema(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<DeleteSchemaRequest>,
+) -> Result<StatusCode, AppError> {
+    let conn = state.connection.lock().await;
+
+    let affected_rows = conn.execute(
+        "DELETE FROM bucket_schemas WHERE bucket = (?);",
+        params![request.bucket],
+    )?;
+
+    info!(message = "Deleted rows", affected_rows);
+
+    if affected_rows == 0 {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::OK)
+    }
+}
+
+/// Check the data already stored in a bucket against the given schema, or the
+/// one attached to the bucket if none is given
+#[tracing::instrument(skip_all, fields( bucket = %request.bucket))]
+pub async fn check_schema(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<CheckSchemaRequest>,
+) -> Result<Json<SchemaCheckResponse>, AppError> {
+    let conn = state.connection.lock().await;
+
+    let validator = match request.schema {
+        Some(schema) => jsonschema::validator_for(&schema)
+            .map_err(|e| AppError::InputError(format!("Invalid schema: {}", e)))?,
+        None => bucket_validator(&conn, &request.bucket)?
+            .ok_or(AppError::Status(StatusCode::NOT_FOUND))?,
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT cast(timestamp as Text), CAST(payload as Text) FROM timeseries WHERE bucket = (?) ORDER BY timestamp ASC;",
+    )?;
+    let mut rows = stmt.query(params![request.bucket])?;
+
+    let mut response = SchemaCheckResponse::default();
+    while let Some(row) = rows.next()? {
+        let payload: String = row.get(1)?;
+        let payload: Value = serde_json::from_str(&payload)?;
+        response.checked += 1;
+
+        let errors = schema_errors(&validator, &payload);
+        if errors.is_empty() {
+            continue;
+        }
+
+        response.failed += 1;
+        if response.failures.len() < MAX_REPORTED_FAILURES {
+            response.failures.push(SchemaCheckFailure {
+                timestamp: row.get(0)?,
+                errors,
+            });
+        }
+    }
+
+    Ok(Json(response))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BucketSchema {
+    bucket: String,
+    schema: Value,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteSchemaRequest {
+    bucket: String,
+}
+
+#[derive(Deserialize)]
+pub struct CheckSchemaRequest {
+    bucket: String,
+    schema: Option<Value>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SchemaCheckResponse {
+    checked: usize,
+    failed: usize,
+    // at most `MAX_REPORTED_FAILURES` failing data points
+    failures: Vec<SchemaCheckFailure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchemaCheckFailure {
+    timestamp: String,
+    errors: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    async fn state_with_points() -> AppState {
+        let state = AppState::in_memory().await;
+        for (timestamp, bucket, payload) in [
+            (
+                "2024-10-01T06:00:00Z",
+                "climate",
+                json!({ "temperature": 21.5 }),
+            ),
+            ("2024-10-01T06:05:00Z", "climate", json!({ "humidity": 40 })),
+            (
+                "2024-10-01T06:10:00Z",
+                "climate",
+                json!({ "temperature": "warm" }),
+            ),
+            ("2024-10-01T06:15:00Z", "co2", json!({ "co2": 612 })),
+        ] {
+            state
+                .connection
+                .lock()
+                .await
+                .execute(
+                    "INSERT INTO timeseries (timestamp, bucket, payload) VALUES (?, ?, ?);",
+                    params![timestamp, bucket, payload.to_string()],
+                )
+                .unwrap();
+        }
+
+        state
+    }
+
+    fn climate_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["temperature"],
+            "properties": { "temperature": { "type": "number" } }
+        })
+    }
+
+    async fn check(state: &AppState, schema: Option<Value>) -> SchemaCheckResponse {
+        let request = CheckSchemaRequest {
+            bucket: "climate".into(),
+            schema,
+        };
+        let Json(response) =
+            check_schema(State(state.clone()), AuthenticatedUser {}, Json(request))
+                .await
+                .unwrap();
+
+        response
+    }
+
+    #[tokio::test]
+    async fn check_reports_stored_points_failing_the_schema() {
+        let state = state_with_points().await;
+
+        let response = check(&state, Some(climate_schema())).await;
+        assert_eq!(response.checked, 3);
+        assert_eq!(response.failed, 2);
+        assert_eq!(response.failures.len(), 2);
+        assert!(response
+            .failures
+            .iter()
+            .all(|failure| !failure.errors.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn check_uses_the_stored_schema() {
+        let state = state_with_points().await;
+
+        let request = CheckSchemaRequest {
+            bucket: "climate".into(),
+            schema: None,
+        };
+        let result = check_schema(State(state.clone()), AuthenticatedUser {}, Json(request)).await;
+        assert!(matches!(
+            result,
+            Err(AppError::Status(StatusCode::NOT_FOUND))
+        ));
+
+        let schema = BucketSchema {
+            bucket: "climate".into(),
+            schema: climate_schema(),
+        };
+        let status = set_schema(State(state.clone()), AuthenticatedUser {}, Json(schema))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        let response = check(&state, None).await;
+        assert_eq!((response.checked, response.failed), (3, 2));
+    }
+
+    #[tokio::test]
+    async fn invalid_schemas_are_not_stored() {
+        let state = state_with_points().await;
+
+        let schema = BucketSchema {
+            bucket: "climate".into(),
+            schema: json!({ "type": "no-such-type" }),
+        };
+        let result = set_schema(State(state.clone()), AuthenticatedUser {}, Json(schema)).await;
+        assert!(matches!(result, Err(AppError::InputError(_))));
+
+        let Json(schemas) = get_schemas(State(state), AuthenticatedUser {})
+            .await
+            .unwrap();
+        assert!(schemas.is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+use axum::{extract::State, http::StatusCode, Json};
+use duckdb::params;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{auth::AuthenticatedUser, error::AppError, ingest::TimestampPolicy, AppState};
+
+/// Global timestamp policy and the ones overriding it per bucket
+#[tracing::instrument(skip_all)]
+pub async fn get_timestamp_policies(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+) -> Result<Json<TimestampPoliciesResponse>, AppError> {
+    let conn = state.connection.lock().await;
+
+    let mut stmt =
+        conn.prepare("SELECT bucket, action, max_past, max_future FROM timestamp_policies;")?;
+    let response: Result<Vec<PolicyRow>, _> = stmt
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect();
+
+    let buckets = response?
+        .into_iter()
+        .map(|(bucket, action, max_past, max_future)| {
+            Ok(BucketTimestampPolicy {
+                bucket,
+                policy: TimestampPolicy {
+                    action: action.parse().map_err(AppError::InputError)?,
+                    max_past,
+                    max_future,
+                },
+            })
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    Ok(Json(TimestampPoliciesResponse {
+        global: state.timestamp_policy.clone(),
+        buckets,
+    }))
+}
+
+#[tracing::instrument(skip_all, fields( bucket = %request.bucket))]
+pub async fn set_timestamp_policy(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<BucketTimestampPolicy>,
+) -> Result<StatusCode, AppError> {
+    let policy = request.policy;
+    if policy.max_past.is_some_and(|max| max < 0) || policy.max_future.is_some_and(|max| max < 0) {
+        return Err(AppError::InputError(
+            "Window bounds must not be negative".into(),
+        ));
+    }
+
+    let conn = state.connection.lock().await;
+    conn.execute(
+        "INSERT OR REPLACE INTO timestamp_policies (bucket, action, max_past, max_future) VALUES (?, ?, ?, ?);",
+        params![
+            request.bucket,
+            policy.action.as_str(),
+            policy.max_past, policy.max_future],
+    )?;
+
+    Ok(StatusCode::OK)
+}
+
+#[tracing::instrument(skip_all, fields( bucket = %request.bucket))]
+pub async fn delete_timestamp_policy(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<DeleteTimestampPolicyRequest>,
+) -> Result<StatusCode, AppError> {
+    let conn = state.connection.lock().await;
+
+    let affected_rows = conn.execute(
+        "DELETE FROM timestamp_policies WHERE bucket = (?);",
+        params![request.bucket],
+    )?;
+
+    info!(message = "Deleted rows", affected_rows);
+
+    if affected_rows == 0 {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::OK)
+    }
+}
+
+// bucket, action, max_past and max_future of a stored policy
+type PolicyRow = (String, String, Option<i64>, Option<i64>);
+
+#[derive(Serialize)]
+pub struct TimestampPoliciesResponse {
+    // applies to buckets without a policy of their own
+    global: Option<TimestampPolicy>,
+    buckets: Vec<BucketTimestampPolicy>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BucketTimestampPolicy {
+    bucket: String,
+    #[serde(flatten)]
+    policy: TimestampPolicy,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteTimestampPolicyRequest {
+    bucket: String,
+}
@@ -0,0 +1,436 @@
+use std::{fs, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use duckdb::Connection;
+use jiff::Timestamp;
+use quick_xml::{events::Event, Reader};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    auth::AuthenticatedEmitter,
+    error::AppError,
+    gps::GPSLocation,
+    ingest::{
+        apply_timestamp_policy, bucket_validator, timestamp_policy, validate_payload,
+        InsertOutcome, Point,
+    },
+    transforms::Transforms,
+    writer::Writer,
+    AppState,
+};
+
+/// Import a GPX or KML file into a GPS bucket. Points are keyed by the file's
+/// hash and their position in it, so importing a file twice is harmless.
+#[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
+pub async fn import_track_file(
+    State(state): State<AppState>,
+    emitter: AuthenticatedEmitter,
+    Path((_, bucket)): Path<(String, String)>,
+    file: String,
+) -> Result<Json<TrackImportResponse>, AppError> {
+    emitter.authorize_bucket(&bucket)?;
+
+    let mut response = TrackImportResponse::default();
+    let mut points = track_points(&bucket, &file, &mut response)?;
+
+    let conn = state.connection.lock().await;
+    let validator = bucket_validator(&conn, &bucket)?;
+    let policy = timestamp_policy(&conn, state.timestamp_policy.as_ref(), &bucket)?;
+    let mut transforms = Transforms::default();
+    for point in points.iter_mut() {
+        apply_timestamp_policy(&conn, policy.as_ref(), &emitter.description, point)?;
+        transforms.apply(&conn, point)?;
+        validate_payload(validator.as_ref(), &point.payload)?;
+    }
+    drop(conn);
+
+    let outcomes = state
+        .writer
+        .write(Some(&emitter.description), points)
+        .await?;
+    response.count(&outcomes);
+    info!(
+        message = "Imported track",
+        imported = response.imported,
+        duplicates = response.duplicates,
+        skipped = response.skipped
+    );
+
+    Ok(Json(response))
+}
+
+/// Import track files from the command line into a GPS bucket
+pub async fn import_track_files(
+    connection: Arc<Mutex<Connection>>,
+    writer: Writer,
+    bucket: &str,
+    files: &[String],
+) -> Result<(), AppError> {
+    for file in files {
+        let content = fs::read_to_string(file)
+            .map_err(|e| AppError::InputError(format!("Failed to read {}: {}", file, e)))?;
+        info!(message = "Importing file", file);
+
+        let mut response = TrackImportResponse::default();
+        let mut points = track_points(bucket, &content, &mut response)?;
+
+        let conn = connection.lock().await;
+        let validator = bucket_validator(&conn, bucket)?;
+        let mut transforms = Transforms::default();
+        for point in points.iter_mut() {
+            transforms.apply(&conn, point)?;
+            validate_payload(validator.as_ref(), &point.payload)?;
+        }
+        drop(conn);
+
+        let outcomes = writer.write(None, points).await?;
+        response.count(&outcomes);
+        info!(
+            message = "Imported track",
+            imported = response.imported,
+            duplicates = response.duplicates,
+            skipped = response.skipped
+        );
+    }
+
+    Ok(())
+}
+
+/// Points of a GPX or KML file, their idempotency key is the hash of the file
+/// and their index in it. Points without a valid timestamp are counted in
+/// `response`.
+fn track_points(
+    bucket: &str,
+    file: &str,
+    response: &mut TrackImportResponse,
+) -> Result<Vec<Point>, AppError> {
+    let track = if file.contains("<gpx") {
+        parse_gpx(file)
+    } else if file.contains("<kml") {
+        parse_kml(file)
+    } else {
+        Err("File is neither GPX nor KML".into())
+    }
+    .map_err(AppError::InputError)?;
+
+    let file_hash = hex::encode(Sha256::digest(file));
+
+    let mut points = Vec::new();
+    for (index, point) in track.into_iter().enumerate() {
+        let Some(Ok(timestamp)) = point.time.as_deref().map(Timestamp::from_str) else {
+            response.skipped += 1;
+            continue;
+        };
+
+        let mut properties = point.extensions;
+        properties.insert("timestamp".into(), timestamp.to_string().into());
+        if let Some(elevation) = point.elevation {
+            properties.insert("altitude".into(), elevation.into());
+        }
+
+        let feature = GPSLocation::point(point.longitude, point.latitude, properties);
+        points.push(Point {
+            timestamp: timestamp.to_string(),
+            bucket: bucket.into(),
+            payload: serde_json::to_value(&feature)?,
+            idempotency_key: Some(format!("{}:{}", file_hash, index)),
+            original_timestamp: None,
+        });
+    }
+
+    Ok(points)
+}
+
+#[derive(Default)]
+struct TrackPoint {
+    time: Option<String>,
+    longitude: f64,
+    latitude: f64,
+    elevation: Option<f64>,
+    extensions: Map<String, Value>,
+}
+
+/// Collect `trkpt` and `rtept` elements with their time, elevation and any
+/// leaf values below `extensions`
+fn parse_gpx(xml: &str) -> Result<Vec<TrackPoint>, String> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut points = Vec::new();
+    let mut current: Option<TrackPoint> = None;
+    let mut path: Vec<String> = Vec::new();
+
+    loop {
+        match reader.read_event().map_err(|e| e.to_string())? {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                if name == "trkpt" || name == "rtept" {
+                    let mut point = TrackPoint::default();
+                    for attribute in e.attributes() {
+                        let attribute = attribute.map_err(|e| e.to_string())?;
+                        let value = attribute.unescape_value().map_err(|e| e.to_string())?;
+                        match attribute.key.local_name().as_ref() {
+                            b"lat" => point.latitude = parse_coordinate(&value)?,
+                            b"lon" => point.longitude = parse_coordinate(&value)?,
+                            _ => {}
+                        }
+                    }
+                    current = Some(point);
+                }
+                path.push(name);
+            }
+            Event::Text(e) => {
+                let Some(point) = current.as_mut() else {
+                    continue;
+                };
+                let text = e.unescape().map_err(|e| e.to_string())?;
+                let parent = path.iter().rev().nth(1).map(|p| p.as_str());
+
+                match (path.last().map(|p| p.as_str()), parent) {
+                    (Some("time"), Some("trkpt" | "rtept")) => point.time = Some(text.into()),
+                    (Some("ele"), Some("trkpt" | "rtept")) => point.elevation = text.parse().ok(),
+                    (Some(name), _) if path.iter().any(|p| p == "extensions") => {
+                        point
+                            .extensions
+                            .insert(name.into(), parse_extension_value(&text));
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(_) => {
+                if let Some(name) = path.pop() {
+                    if name == "trkpt" || name == "rtept" {
+                        points.extend(current.take());
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(points)
+}
+
+/// Collect points from `gx:Track` elements and from placemarks with a
+/// timestamp and a point geometry
+fn parse_kml(xml: &str) -> Result<Vec<TrackPoint>, String> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut points = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut whens: Vec<String> = Vec::new();
+    let mut coords: Vec<String> = Vec::new();
+
+    loop {
+        match reader.read_event().map_err(|e| e.to_string())? {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                if name == "Track" || name == "Placemark" {
+                    whens.clear();
+                    coords.clear();
+                }
+                path.push(name);
+            }
+            Event::Text(e) => {
+                let text = e.unescape().map_err(|e| e.to_string())?;
+                match path.last().map(|p| p.as_str()) {
+                    Some("when") => whens.push(text.into()),
+                    // gx:coord separates by spaces, Point coordinates by commas
+                    Some("coord") => coords.push(text.replace(' ', ",")),
+                    Some("coordinates") if path.iter().any(|p| p == "Point") => {
+                        coords.push(text.into())
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(_) => {
+                let Some(name) = path.pop() else {
+                    continue;
+                };
+                if name != "Track" && name != "Placemark" {
+                    continue;
+                }
+
+                for (when, coord) in whens.drain(..).zip(coords.drain(..)) {
+                    let mut values = coord.split(',').map(|v| v.trim());
+                    let (Some(longitude), Some(latitude)) = (values.next(), values.next()) else {
+                        return Err(format!("Invalid coordinate {}", coord));
+                    };
+
+                    points.push(TrackPoint {
+                        time: Some(when),
+                        longitude: parse_coordinate(longitude)?,
+                        latitude: parse_coordinate(latitude)?,
+                        elevation: values.next().and_then(|v| v.parse().ok()),
+                        extensions: Map::new(),
+                    });
+                }
+                whens.clear();
+                coords.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(points)
+}
+
+fn parse_coordinate(value: &str) -> Result<f64, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid coordinate {}", value))
+}
+
+fn parse_extension_value(value: &str) -> Value {
+    match value
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+    {
+        Some(number) => Value::Number(number),
+        None => Value::String(value.into()),
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TrackImportResponse {
+    imported: usize,
+    // points already present in the bucket
+    duplicates: usize,
+    // points without a valid timestamp
+    skipped: usize,
+}
+
+impl TrackImportResponse {
+    fn count(&mut self, outcomes: &[InsertOutcome]) {
+        for outcome in outcomes {
+            match outcome {
+                InsertOutcome::Inserted => self.imported += 1,
+                InsertOutcome::Duplicate => self.duplicates += 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v1">
+  <trk>
+    <name>Morning run</name>
+    <trkseg>
+      <trkpt lat="47.3769" lon="8.5417">
+        <ele>408.5</ele>
+        <time>2024-10-01T06:00:00Z</time>
+        <extensions>
+          <gpxtpx:TrackPointExtension>
+            <gpxtpx:hr>142</gpxtpx:hr>
+            <gpxtpx:note>easy &amp; slow</gpxtpx:note>
+          </gpxtpx:TrackPointExtension>
+        </extensions>
+      </trkpt>
+      <trkpt lat="47.3770" lon="8.5420">
+        <time>2024-10-01T06:00:05Z</time>
+      </trkpt>
+    </trkseg>
+  </trk>
+  <rte>
+    <rtept lat="47.5" lon="8.7"><name>Summit</name></rtept>
+  </rte>
+</gpx>"#;
+
+    const KML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">
+  <Document>
+    <Placemark>
+      <gx:Track>
+        <when>2024-10-01T06:00:00Z</when>
+        <when>2024-10-01T06:00:05Z</when>
+        <gx:coord>8.5417 47.3769 408.5</gx:coord>
+        <gx:coord>8.5420 47.3770 409</gx:coord>
+      </gx:Track>
+    </Placemark>
+    <Placemark>
+      <TimeStamp><when>2024-10-01T07:00:00Z</when></TimeStamp>
+      <Point><coordinates>8.6,47.4</coordinates></Point>
+    </Placemark>
+    <Placemark>
+      <LineString><coordinates>8.6,47.4 8.7,47.5</coordinates></LineString>
+    </Placemark>
+  </Document>
+</kml>"#;
+
+    #[test]
+    fn parses_gpx_track_and_route_points() {
+        let points = parse_gpx(GPX).unwrap();
+        assert_eq!(points.len(), 3);
+
+        let first = &points[0];
+        assert_eq!(first.time.as_deref(), Some("2024-10-01T06:00:00Z"));
+        assert_eq!((first.longitude, first.latitude), (8.5417, 47.3769));
+        assert_eq!(first.elevation, Some(408.5));
+        assert_eq!(
+            Value::Object(first.extensions.clone()),
+            json!({ "hr": 142.0, "note": "easy & slow" })
+        );
+
+        assert_eq!(points[1].elevation, None);
+        assert!(points[1].extensions.is_empty());
+
+        // Route points have no time and are skipped on import
+        assert_eq!(points[2].time, None);
+        assert_eq!((points[2].longitude, points[2].latitude), (8.7, 47.5));
+    }
+
+    #[test]
+    fn rejects_invalid_gpx_coordinates() {
+        let gpx = r#"<gpx><trk><trkseg><trkpt lat="north" lon="8.5"></trkpt></trkseg></trk></gpx>"#;
+        assert_eq!(parse_gpx(gpx).err().unwrap(), "Invalid coordinate north");
+    }
+
+    #[test]
+    fn parses_kml_tracks_and_placemarks() {
+        let points = parse_kml(KML).unwrap();
+        let parsed: Vec<(&str, f64, f64, Option<f64>)> = points
+            .iter()
+            .map(|p| {
+                (
+                    p.time.as_deref().unwrap(),
+                    p.longitude,
+                    p.latitude,
+                    p.elevation,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            parsed,
+            [
+                ("2024-10-01T06:00:00Z", 8.5417, 47.3769, Some(408.5)),
+                ("2024-10-01T06:00:05Z", 8.542, 47.377, Some(409.0)),
+                ("2024-10-01T07:00:00Z", 8.6, 47.4, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_kml_coordinates() {
+        let kml = r#"<kml><Placemark><TimeStamp><when>2024-10-01T07:00:00Z</when></TimeStamp><Point><coordinates>8.6</coordinates></Point></Placemark></kml>"#;
+        assert_eq!(parse_kml(kml).err().unwrap(), "Invalid coordinate 8.6");
+    }
+}
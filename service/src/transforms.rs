@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+
+use axum::{extract::State, http::StatusCode, Json};
+use duckdb::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use tracing::info;
+
+use crate::{auth::AuthenticatedUser, error::AppError, ingest::Point, AppState};
+
+/// A single step of a bucket's transformation. Fields are addressed by dotted
+/// paths into the payload, e.g. `sensor.temperature`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TransformRule {
+    Rename { field: String, to: String },
+    Scale { field: String, factor: f64 },
+    Offset { field: String, value: f64 },
+    Drop { field: String },
+    Constant { field: String, value: Value },
+    // arithmetic over numeric fields and literals, e.g. `temperature * 1.8 + 32`
+    Compute { field: String, expression: String },
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_transforms(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+) -> Result<Json<Vec<BucketTransform>>, AppError> {
+    let conn = state.connection.lock().await;
+
+    let mut stmt = conn.prepare("SELECT bucket, CAST(rules as Text) FROM bucket_transforms;")?;
+    let response: Result<Vec<(String, String)>, _> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect();
+
+    let response = response?
+        .into_iter()
+        .map(|(bucket, rules)| {
+            Ok(BucketTransform {
+                bucket,
+                rules: serde_json::from_str(&rules)?,
+            })
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    Ok(Json(response))
+}
+
+#[tracing::instrument(skip_all, fields( bucket = %request.bucket))]
+pub async fn set_transform(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<BucketTransform>,
+) -> Result<StatusCode, AppError> {
+    check_rules(&request.rules)?;
+
+    let conn = state.connection.lock().await;
+    conn.execute(
+        "INSERT OR REPLACE INTO bucket_transforms (bucket, rules) VALUES (?, ?);",
+        params![request.bucket, serde_json::to_string(&request.rules)?],
+    )?;
+
+    Ok(StatusCode::OK)
+}
+
+#[tracing::instrument(skip_all, fields( bucket = %request.bucket))]
+pub async fn delete_transform(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<DeleteTransformRequest>,
+) -> Result<StatusCode, AppError> {
+    let conn = state.connection.lock().await;
+
+    let affected_rows = conn.execute(
+        "DELETE FROM bucket_transforms WHERE bucket = (?);",
+        params![request.bucket],
+    )?;
+
+    info!(message = "Deleted rows", affected_rows);
+
+    if affected_rows == 0 {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::OK)
+    }
+}
+
+/// Run a payload through the given rules, or the ones of the bucket if none
+/// are given, without storing anything
+#[tracing::instrument(skip_all)]
+pub async fn test_transform(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<TestTransformRequest>,
+) -> Result<Json<TestTransformResponse>, AppError> {
+    let rules = match (request.rules, request.bucket) {
+        (Some(rules), _) => {
+            check_rules(&rules)?;
+            rules
+        }
+        (None, Some(bucket)) => {
+            let conn = state.connection.lock().await;
+            bucket_transform(&conn, &bucket)?.ok_or(AppError::Status(StatusCode::NOT_FOUND))?
+        }
+        (None, None) => {
+            return Err(AppError::InputError(
+                "Either bucket or rules required".into(),
+            ))
+        }
+    };
+
+    let mut output = request.payload.clone();
+    apply_rules(&rules, &mut output);
+
+    Ok(Json(TestTransformResponse {
+        input: request.payload,
+        output,
+    }))
+}
+
+/// Transformation rules of `bucket`, if any
+pub fn bucket_transform(
+    conn: &Connection,
+    bucket: &str,
+) -> Result<Option<Vec<TransformRule>>, AppError> {
+    let mut stmt = conn
+        .prepare_cached("SELECT CAST(rules as Text) FROM bucket_transforms WHERE bucket = (?);")?;
+    let mut rows = stmt.query(params![bucket])?;
+
+    let Some(row) = rows.next()? else {
+        return Ok(None);
+    };
+    let rules: String = row.get(0)?;
+
+    Ok(Some(serde_json::from_str(&rules)?))
+}
+
+/// Transform rules by bucket, loaded once per request. Ingest paths apply
+/// them before schema validation, so schemas describe the stored payloads.
+#[derive(Default)]
+pub struct Transforms {
+    rules: HashMap<String, Option<Vec<TransformRule>>>,
+}
+
+impl Transforms {
+    /// Apply the rules of the point's bucket to its payload
+    pub fn apply(&mut self, conn: &Connection, point: &mut Point) -> Result<(), AppError> {
+        if !self.rules.contains_key(&point.bucket) {
+            let rules = bucket_transform(conn, &point.bucket)?;
+            self.rules.insert(point.bucket.clone(), rules);
+        }
+        if let Some(rules) = &self.rules[&point.bucket] {
+            apply_rules(rules, &mut point.payload);
+        }
+
+        Ok(())
+    }
+}
+
+/// Apply rules in order. Rules referring to missing or non-numeric fields are
+/// skipped so a partial payload is stored rather than rejected.
+pub fn apply_rules(rules: &[TransformRule], payload: &mut Value) {
+    for rule in rules {
+        match rule {
+            TransformRule::Rename { field, to } => {
+                if let Some(value) = remove_field(payload, field) {
+                    set_field(payload, to, value);
+                }
+            }
+            TransformRule::Scale { field, factor } => {
+                if let Some(value) = number_field(payload, field) {
+                    set_number(payload, field, value * factor);
+                }
+            }
+            TransformRule::Offset {
+                field,
+                value: offset,
+            } => {
+                if let Some(value) = number_field(payload, field) {
+                    set_number(payload, field, value + offset);
+                }
+            }
+            TransformRule::Drop { field } => {
+                remove_field(payload, field);
+            }
+            TransformRule::Constant { field, value } => set_field(payload, field, value.clone()),
+            TransformRule::Compute { field, expression } => {
+                // Expressions are checked when rules are stored
+                let value = parse_expression(expression)
+                    .ok()
+                    .and_then(|expression| expression.evaluate(payload));
+                if let Some(value) = value {
+                    set_number(payload, field, value);
+                }
+            }
+        }
+    }
+}
+
+fn check_rules(rules: &[TransformRule]) -> Result<(), AppError> {
+    for rule in rules {
+        if let TransformRule::Compute { field, expression } = rule {
+            parse_expression(expression).map_err(|e| {
+                AppError::InputError(format!("Invalid expression for {}: {}", field, e))
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+fn get_field<'a>(payload: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(payload, |value, key| value.get(key))
+}
+
+pub(crate) fn number_field(payload: &Value, path: &str) -> Option<f64> {
+    get_field(payload, path)?.as_f64()
+}
+
+fn remove_field(payload: &mut Value, path: &str) -> Option<Value> {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (
+            parent
+                .split('.')
+                .try_fold(payload, |value, key| value.get_mut(key))?,
+            key,
+        ),
+        None => (payload, path),
+    };
+
+    parent.as_object_mut()?.remove(key)
+}
+
+/// Set a field, creating missing parent objects. Paths running into
+/// non-object values are left alone.
+fn set_field(payload: &mut Value, path: &str, value: Value) {
+    let mut keys = path.split('.').peekable();
+    let mut current = payload;
+
+    while let Some(key) = keys.next() {
+        let Some(object) = current.as_object_mut() else {
+            return;
+        };
+        if keys.peek().is_none() {
+            object.insert(key.into(), value);
+            return;
+        }
+        current = object
+            .entry(key.to_string())
+            .or_insert(Value::Object(Map::new()));
+    }
+}
+
+fn set_number(payload: &mut Value, path: &str, value: f64) {
+    // Results like division by zero can't be represented in JSON
+    if let Some(number) = Number::from_f64(value) {
+        set_field(payload, path, Value::Number(number));
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Expression {
+    Number(f64),
+    Field(String),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, char, Box<Expression>),
+}
+
+impl Expression {
+    fn evaluate(&self, payload: &Value) -> Option<f64> {
+        match self {
+            Expression::Number(value) => Some(*value),
+            Expression::Field(path) => number_field(payload, path),
+            Expression::Negate(expression) => Some(-expression.evaluate(payload)?),
+            Expression::Binary(left, operator, right) => {
+                let (left, right) = (left.evaluate(payload)?, right.evaluate(payload)?);
+                match operator {
+                    '+' => Some(left + right),
+                    '-' => Some(left - right),
+                    '*' => Some(left * right),
+                    '/' => Some(left / right),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Field(String),
+    Operator(char),
+    Open,
+    Close,
+}
+
+fn parse_expression(input: &str) -> Result<Expression, String> {
+    let tokens = tokenize(input)?;
+    let mut position = 0;
+    let expression = parse_sum(&tokens, &mut position)?;
+
+    match tokens.get(position) {
+        None => Ok(expression),
+        Some(token) => Err(format!("Unexpected {:?}", token)),
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => {}
+            '+' | '-' | '*' | '/' => tokens.push(Token::Operator(c)),
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '0'..='9' | '.' => {
+                let mut number = String::from(c);
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+                    number.push(c);
+                }
+                let number = number
+                    .parse()
+                    .map_err(|_| format!("Invalid number {}", number))?;
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut field = String::from(c);
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_' || *c == '.')
+                {
+                    field.push(c);
+                }
+                tokens.push(Token::Field(field));
+            }
+            _ => return Err(format!("Unexpected character {}", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_sum(tokens: &[Token], position: &mut usize) -> Result<Expression, String> {
+    let mut expression = parse_product(tokens, position)?;
+    while let Some(Token::Operator(operator @ ('+' | '-'))) = tokens.get(*position) {
+        *position += 1;
+        let right = parse_product(tokens, position)?;
+        expression = Expression::Binary(Box::new(expression), *operator, Box::new(right));
+    }
+
+    Ok(expression)
+}
+
+fn parse_product(tokens: &[Token], position: &mut usize) -> Result<Expression, String> {
+    let mut expression = parse_factor(tokens, position)?;
+    while let Some(Token::Operator(operator @ ('*' | '/'))) = tokens.get(*position) {
+        *position += 1;
+        let right = parse_factor(tokens, position)?;
+        expression = Expression::Binary(Box::new(expression), *operator, Box::new(right));
+    }
+
+    Ok(expression)
+}
+
+fn parse_factor(tokens: &[Token], position: &mut usize) -> Result<Expression, String> {
+    let token = tokens
+        .get(*position)
+        .ok_or("Unexpected end of expression")?;
+    *position += 1;
+
+    match token {
+        Token::Number(value) => Ok(Expression::Number(*value)),
+        Token::Field(path) => Ok(Expression::Field(path.clone())),
+        Token::Operator('-') => Ok(Expression::Negate(Box::new(parse_factor(
+            tokens, position,
+        )?))),
+        Token::Open => {
+            let expression = parse_sum(tokens, position)?;
+            match tokens.get(*position) {
+                Some(Token::Close) => {
+                    *position += 1;
+                    Ok(expression)
+                }
+                _ => Err("Missing closing parenthesis".into()),
+            }
+        }
+        token => Err(format!("Unexpected {:?}", token)),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BucketTransform {
+    bucket: String,
+    rules: Vec<TransformRule>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteTransformRequest {
+    bucket: String,
+}
+
+#[derive(Deserialize)]
+pub struct TestTransformRequest {
+    bucket: Option<String>,
+    // rules to try instead of the ones stored for `bucket`
+    rules: Option<Vec<TransformRule>>,
+    payload: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TestTransformResponse {
+    input: Value,
+    output: Value,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rules(rules: Value) -> Vec<TransformRule> {
+        serde_json::from_value(rules).unwrap()
+    }
+
+    fn transform(rules_json: Value, mut payload: Value) -> Value {
+        apply_rules(&rules(rules_json), &mut payload);
+        payload
+    }
+
+    #[test]
+    fn renames_and_drops_fields() {
+        let payload = transform(
+            json!([
+                { "type": "rename", "field": "temp", "to": "temperature" },
+                { "type": "rename", "field": "sensor.hum", "to": "humidity" },
+                { "type": "drop", "field": "sensor.raw" },
+                { "type": "rename", "field": "missing", "to": "other" },
+            ]),
+            json!({ "temp": 21.5, "sensor": { "hum": 40, "raw": "0x1f", "id": 7 } }),
+        );
+
+        assert_eq!(
+            payload,
+            json!({ "temperature": 21.5, "humidity": 40, "sensor": { "id": 7 } })
+        );
+    }
+
+    #[test]
+    fn scales_and_offsets_numbers() {
+        let payload = transform(
+            json!([
+                { "type": "scale", "field": "battery", "factor": 0.01 },
+                { "type": "offset", "field": "sensor.temperature", "value": -273.15 },
+                { "type": "scale", "field": "room", "factor": 2 },
+                { "type": "offset", "field": "missing", "value": 1 },
+            ]),
+            json!({ "battery": 50, "sensor": { "temperature": 300 }, "room": "office" }),
+        );
+
+        assert_eq!(payload["battery"], json!(0.5));
+        assert!((payload["sensor"]["temperature"].as_f64().unwrap() - 26.85).abs() < 1e-9);
+        // Non-numeric and missing fields are left alone
+        assert_eq!(payload["room"], json!("office"));
+        assert_eq!(payload.get("missing"), None);
+    }
+
+    #[test]
+    fn sets_constants_creating_parents() {
+        let payload = transform(
+            json!([
+                { "type": "constant", "field": "source", "value": "import" },
+                { "type": "constant", "field": "meta.version", "value": 2 },
+                { "type": "constant", "field": "room.name", "value": "office" },
+            ]),
+            json!({ "room": "kitchen" }),
+        );
+
+        // Paths running into non-object values are skipped
+        assert_eq!(
+            payload,
+            json!({ "source": "import", "meta": { "version": 2 }, "room": "kitchen" })
+        );
+    }
+
+    #[test]
+    fn computes_fields_from_expressions() {
+        let payload = transform(
+            json!([
+                { "type": "compute", "field": "fahrenheit", "expression": "celsius * 1.8 + 32" },
+                { "type": "compute", "field": "power", "expression": "sensor.voltage * (sensor.current - -0.5)" },
+                { "type": "compute", "field": "ratio", "expression": "celsius / 0" },
+                { "type": "compute", "field": "unknown", "expression": "missing + 1" },
+            ]),
+            json!({ "celsius": 20, "sensor": { "voltage": 230, "current": 1.5 } }),
+        );
+
+        assert_eq!(payload["fahrenheit"], json!(68.0));
+        assert_eq!(payload["power"], json!(460.0));
+        // Infinity can't be stored and missing inputs give no result
+        assert_eq!(payload.get("ratio"), None);
+        assert_eq!(payload.get("unknown"), None);
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let payload = transform(
+            json!([
+                { "type": "rename", "field": "temp", "to": "celsius" },
+                { "type": "compute", "field": "kelvin", "expression": "celsius + 273" },
+                { "type": "drop", "field": "celsius" },
+            ]),
+            json!({ "temp": 20 }),
+        );
+
+        assert_eq!(payload, json!({ "kelvin": 293.0 }));
+    }
+
+    #[test]
+    fn parses_expressions_with_precedence() {
+        let number = |value| Box::new(Expression::Number(value));
+        let field = |path: &str| Box::new(Expression::Field(path.into()));
+
+        assert_eq!(
+            parse_expression("a + 2 * b.c"),
+            Ok(Expression::Binary(
+                field("a"),
+                '+',
+                Box::new(Expression::Binary(number(2.0), '*', field("b.c")))
+            ))
+        );
+        assert_eq!(
+            parse_expression("(a + 2) * -b"),
+            Ok(Expression::Binary(
+                Box::new(Expression::Binary(field("a"), '+', number(2.0))),
+                '*',
+                Box::new(Expression::Negate(field("b")))
+            ))
+        );
+        // Operators of the same precedence are left associative
+        assert_eq!(
+            parse_expression("8 - 4 - 2").unwrap().evaluate(&json!({})),
+            Some(2.0)
+        );
+        assert_eq!(
+            parse_expression("8 / 4 / 2").unwrap().evaluate(&json!({})),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for (expression, error) in [
+            ("", "Unexpected end of expression"),
+            ("a +", "Unexpected end of expression"),
+            ("(a + 1", "Missing closing parenthesis"),
+            ("a + 1)", "Unexpected Close"),
+            ("a b", "Unexpected Field(\"b\")"),
+            ("a % 2", "Unexpected character %"),
+            ("1.2.3", "Invalid number 1.2.3"),
+            ("* 2", "Unexpected Operator('*')"),
+        ] {
+            assert_eq!(
+                parse_expression(expression),
+                Err(error.into()),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn checks_expressions_of_stored_rules() {
+        assert!(check_rules(&rules(json!([
+            { "type": "compute", "field": "f", "expression": "c * 1.8 + 32" },
+            { "type": "drop", "field": "c" },
+        ])))
+        .is_ok());
+
+        let error = check_rules(&rules(json!([
+            { "type": "compute", "field": "f", "expression": "c *" },
+        ])))
+        .err()
+        .unwrap();
+        assert!(
+            matches!(&error, AppError::InputError(e) if e == "Invalid expression for f: Unexpected end of expression")
+        );
+    }
+}
@@ -1,7 +1,11 @@
+use std::str::FromStr;
+
+use jiff::Timestamp;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use serde::Deserialize;
 
-use crate::data::DataResponse;
+use crate::{data::DataResponse, transforms::number_field};
 
 const AUTH_TOKEN_LENGTH: usize = 64;
 
@@ -14,25 +18,330 @@ pub fn get_auth_token() -> String {
         .collect::<String>()
 }
 
-pub fn sample(n: Option<u32>, data: Vec<DataResponse>) -> Vec<DataResponse> {
-    if let Some(n) = n {
-        if n > data.len() as u32 {
-            return data;
+/// Match `text` against a glob `pattern` where `*` matches any sequence of
+/// characters and `?` any single character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and the text position it currently covers up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, covered)) = backtrack {
+            p = star + 1;
+            t = covered + 1;
+            backtrack = Some((star, covered + 1));
+        } else {
+            return false;
         }
-        let mut sampled = Vec::with_capacity(n as usize);
-        let ratio = n as f64 / data.len() as f64;
-        let mut acc = 0.0;
-
-        for d in data.into_iter() {
-            acc += ratio;
-            if acc > 1.0 {
-                sampled.push(d);
-                acc -= 1.0;
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// How `get_data` reduces a response to the requested number of points
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleMethod {
+    // evenly spaced points
+    #[default]
+    Stride,
+    // Largest-Triangle-Three-Buckets on a numeric payload field
+    Lttb,
+    // minimum and maximum of a numeric payload field per bucket
+    MinMax,
+}
+
+/// Reduce `data` to exactly `n` points, or return it unchanged if it doesn't
+/// hold more. LTTB and min/max sampling consider only points where `field`, a
+/// dotted path into the payload, is a number and keep the extremes of that
+/// field, which stride sampling may drop.
+pub fn sample(
+    n: Option<u32>,
+    method: SampleMethod,
+    field: Option<&str>,
+    data: Vec<DataResponse>,
+) -> Vec<DataResponse> {
+    let Some(n) = n.map(|n| n as usize) else {
+        return data;
+    };
+
+    let (data, values): (Vec<DataResponse>, Vec<(f64, f64)>) = match (method, field) {
+        (SampleMethod::Lttb | SampleMethod::MinMax, Some(field)) => data
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, d)| {
+                let y = number_field(&d.payload, field)?;
+                // Fall back to the position for the (unexpected) unparseable timestamp
+                let x = Timestamp::from_str(&d.timestamp)
+                    .map(|ts| ts.as_millisecond() as f64)
+                    .unwrap_or(index as f64);
+                Some((d, (x, y)))
+            })
+            .unzip(),
+        _ => (data, Vec::new()),
+    };
+    if n >= data.len() {
+        return data;
+    }
+
+    let selected = match (method, field) {
+        (SampleMethod::Lttb, Some(_)) => lttb(&values, n),
+        (SampleMethod::MinMax, Some(_)) => min_max(&values, n),
+        _ => (0..n).map(|i| i * data.len() / n).collect(),
+    };
+
+    let mut keep = vec![false; data.len()];
+    for index in selected {
+        keep[index] = true;
+    }
+
+    data.into_iter()
+        .zip(keep)
+        .filter_map(|(d, keep)| keep.then_some(d))
+        .collect()
+}
+
+/// Indices of the `n` points chosen by Largest-Triangle-Three-Buckets. The
+/// first and last point are always kept, from each bucket in between the
+/// point spanning the largest triangle with the previously chosen point and
+/// the average of the next bucket.
+fn lttb(points: &[(f64, f64)], n: usize) -> Vec<usize> {
+    let len = points.len();
+    if n >= len {
+        return (0..len).collect();
+    }
+    if n < 3 {
+        return [0, len - 1].into_iter().take(n).collect();
+    }
+
+    // Buckets split the points between the first and the last one
+    let bucket_start = |i: usize| 1 + i * (len - 2) / (n - 2);
+
+    let mut selected = Vec::with_capacity(n);
+    selected.push(0);
+    let mut previous = 0;
+    for i in 0..n - 2 {
+        let (start, end) = (bucket_start(i), bucket_start(i + 1));
+        let next = &points[end..bucket_start(i + 2).min(len)];
+        let next_x = next.iter().map(|(x, _)| x).sum::<f64>() / next.len() as f64;
+        let next_y = next.iter().map(|(_, y)| y).sum::<f64>() / next.len() as f64;
+
+        let (ax, ay) = points[previous];
+        let area = |index: usize| {
+            let (x, y) = points[index];
+            ((ax - next_x) * (y - ay) - (ax - x) * (next_y - ay)).abs()
+        };
+        previous = (start..end)
+            .max_by(|a, b| area(*a).total_cmp(&area(*b)))
+            .unwrap_or(start);
+        selected.push(previous);
+    }
+    selected.push(len - 1);
+
+    selected
+}
+
+/// Indices of the minimum and maximum point of `n / 2` buckets. For odd `n`
+/// the first point is kept as well.
+fn min_max(points: &[(f64, f64)], n: usize) -> Vec<usize> {
+    let len = points.len();
+    if n >= len {
+        return (0..len).collect();
+    }
+
+    let mut selected = Vec::with_capacity(n);
+    let offset = n % 2;
+    if offset == 1 {
+        selected.push(0);
+    }
+
+    // `n <= len` makes every bucket hold at least two points
+    let buckets = n / 2;
+    let bucket_start = |i: usize| offset + i * (len - offset) / buckets.max(1);
+    for i in 0..buckets {
+        let bucket = bucket_start(i)..bucket_start(i + 1);
+        let y = |index: &usize| points[*index].1;
+        // The first minimum and the last maximum differ even if all values are equal
+        let min = bucket
+            .clone()
+            .min_by(|a, b| y(a).total_cmp(&y(b)))
+            .unwrap_or(bucket.start);
+        let max = bucket
+            .clone()
+            .max_by(|a, b| y(a).total_cmp(&y(b)))
+            .unwrap_or(bucket.end - 1);
+        selected.push(min.min(max));
+        selected.push(min.max(max));
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // CO2 readings every 30s around 400ppm with a single spike and dip
+    fn readings(len: usize, spike: usize, dip: usize) -> Vec<DataResponse> {
+        (0..len)
+            .map(|i| {
+                let co2 = match i {
+                    i if i == spike => 2000.0,
+                    i if i == dip => 100.0,
+                    i => 400.0 + (i % 7) as f64,
+                };
+                DataResponse {
+                    timestamp: Timestamp::from_second(1_700_000_000 + 30 * i as i64)
+                        .unwrap()
+                        .to_string(),
+                    bucket: "co2".into(),
+                    payload: json!({ "sensor": { "co2": co2 } }),
+                }
+            })
+            .collect()
+    }
+
+    fn co2(data: &[DataResponse]) -> Vec<f64> {
+        data.iter()
+            .map(|d| number_field(&d.payload, "sensor.co2").unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn stride_returns_requested_count() {
+        for n in [1, 2, 3, 7, 33, 99, 100] {
+            let sampled = sample(Some(n), SampleMethod::Stride, None, readings(100, 10, 20));
+            assert_eq!(sampled.len(), n as usize);
+        }
+    }
+
+    #[test]
+    fn fewer_points_than_requested_are_returned_unchanged() {
+        let sampled = sample(
+            Some(50),
+            SampleMethod::Lttb,
+            Some("sensor.co2"),
+            readings(20, 3, 4),
+        );
+        assert_eq!(sampled.len(), 20);
+        let sampled = sample(None, SampleMethod::Stride, None, readings(20, 3, 4));
+        assert_eq!(sampled.len(), 20);
+    }
+
+    #[test]
+    fn lttb_keeps_extremes() {
+        for n in [3, 10, 50, 101] {
+            let sampled = sample(
+                Some(n),
+                SampleMethod::Lttb,
+                Some("sensor.co2"),
+                readings(2880, 1337, 2001),
+            );
+            let values = co2(&sampled);
+
+            assert_eq!(sampled.len(), n as usize);
+            assert!(values.contains(&2000.0), "spike dropped for n = {}", n);
+            if n > 3 {
+                assert!(values.contains(&100.0), "dip dropped for n = {}", n);
             }
         }
+    }
+
+    #[test]
+    fn lttb_keeps_first_and_last_point() {
+        let data = readings(500, 100, 200);
+        let (first, last) = (data[0].timestamp.clone(), data[499].timestamp.clone());
+
+        let sampled = sample(Some(25), SampleMethod::Lttb, Some("sensor.co2"), data);
+        assert_eq!(sampled[0].timestamp, first);
+        assert_eq!(sampled[24].timestamp, last);
+    }
+
+    #[test]
+    fn min_max_keeps_extremes() {
+        for n in [2, 5, 20, 21, 99] {
+            let sampled = sample(
+                Some(n),
+                SampleMethod::MinMax,
+                Some("sensor.co2"),
+                readings(2880, 1337, 2001),
+            );
+            let values = co2(&sampled);
+
+            assert_eq!(sampled.len(), n as usize);
+            assert!(values.contains(&2000.0), "spike dropped for n = {}", n);
+            assert!(values.contains(&100.0), "dip dropped for n = {}", n);
+        }
+    }
+
+    #[test]
+    fn min_max_handles_constant_values() {
+        let data: Vec<DataResponse> = readings(10, 0, 1)
+            .into_iter()
+            .map(|mut d| {
+                d.payload = json!({ "sensor": { "co2": 400 } });
+                d
+            })
+            .collect();
+
+        let sampled = sample(Some(4), SampleMethod::MinMax, Some("sensor.co2"), data);
+        assert_eq!(sampled.len(), 4);
+    }
+
+    #[test]
+    fn points_without_numeric_field_are_skipped() {
+        let mut data = readings(100, 10, 20);
+        data[50].payload = json!({ "sensor": { "co2": "n/a" } });
+        data[60].payload = json!({});
+
+        let sampled = sample(Some(98), SampleMethod::Lttb, Some("sensor.co2"), data);
+        assert_eq!(sampled.len(), 98);
+        assert_eq!(co2(&sampled).len(), 98);
+    }
+
+    #[test]
+    fn glob_matches_exact_names() {
+        assert!(glob_match("co2", "co2"));
+        assert!(!glob_match("co2", "co2-office"));
+        assert!(!glob_match("co2-office", "co2"));
+        assert!(!glob_match("co2", "CO2"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "co2"));
+    }
+
+    #[test]
+    fn glob_star_matches_any_sequence() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("sensors-*", "sensors-"));
+        assert!(glob_match("sensors-*", "sensors-kitchen"));
+        assert!(glob_match("*-office", "co2-office"));
+        assert!(glob_match("home-*-co2", "home-kitchen-co2"));
+        // The star has to backtrack past an early partial match
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("a*b*c", "abbbbc"));
+        assert!(!glob_match("sensors-*", "sensors"));
+        assert!(!glob_match("sensors-*", "gps"));
+        assert!(!glob_match("*-office", "co2-office-2"));
+        assert!(!glob_match("a*b*c", "abcb"));
+    }
 
-        sampled
-    } else {
-        data
+    #[test]
+    fn glob_question_mark_matches_one_character() {
+        assert!(glob_match("room-?", "room-1"));
+        assert!(!glob_match("room-?", "room-"));
+        assert!(!glob_match("room-?", "room-12"));
+        assert!(glob_match("room-??*", "room-12"));
+        assert!(glob_match("tür-?", "tür-ä"));
     }
 }
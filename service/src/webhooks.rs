@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use duckdb::{params, Connection};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tracing::{error, info};
+
+use crate::{
+    auth::{AuthenticatedEmitter, AuthenticatedUser},
+    error::AppError,
+    ingest::{
+        apply_timestamp_policy, bucket_validator, derive_idempotency_key, idempotency_key,
+        timestamp_policy, validate_payload, InsertOutcome,
+    },
+    mapping::JsonMapping,
+    transforms::Transforms,
+    utils::get_auth_token,
+    AppState,
+};
+
+const DEFAULT_SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Receive a JSON body for the named webhook and map it to data points as
+/// configured. The token may be sent in a header or as last path segment.
+#[tracing::instrument(skip_all, fields( emitter = %emitter.description))]
+pub async fn receive_webhook(
+    State(state): State<AppState>,
+    emitter: AuthenticatedEmitter,
+    headers: HeaderMap,
+    Path(path): Path<BTreeMap<String, String>>,
+    body: Bytes,
+) -> Result<Json<WebhookResponse>, AppError> {
+    let name = path.get("name").cloned().unwrap_or_default();
+    let conn = state.connection.lock().await;
+
+    let (webhook_emitter, webhook) =
+        webhook(&conn, &name)?.ok_or(AppError::Status(StatusCode::NOT_FOUND))?;
+    if webhook_emitter != emitter.description {
+        error!(message = "Token belongs to another emitter", webhook = %name);
+        return Err(AppError::Status(StatusCode::FORBIDDEN));
+    }
+    emitter.authorize_bucket(&webhook.bucket)?;
+
+    if let Some(secret) = &webhook.hmac_secret {
+        let header = webhook
+            .hmac_header
+            .as_deref()
+            .unwrap_or(DEFAULT_SIGNATURE_HEADER);
+        verify_signature(
+            secret,
+            headers.get(header).and_then(|v| v.to_str().ok()),
+            &body,
+        )?;
+    }
+
+    let body: Value = serde_json::from_slice(&body)
+        .map_err(|e| AppError::InputError(format!("Invalid JSON body: {}", e)))?;
+
+    let request_key = idempotency_key(&headers);
+    let validator = bucket_validator(&conn, &webhook.bucket)?;
+    let policy = timestamp_policy(&conn, state.timestamp_policy.as_ref(), &webhook.bucket)?;
+    let mut transforms = Transforms::default();
+
+    let mut points = webhook.mapping.points(&body, &webhook.bucket)?;
+    for (index, point) in points.iter_mut().enumerate() {
+        point.idempotency_key = derive_idempotency_key(&request_key, index);
+        apply_timestamp_policy(&conn, policy.as_ref(), &emitter.description, point)?;
+        transforms.apply(&conn, point)?;
+        validate_payload(validator.as_ref(), &point.payload)?;
+    }
+    drop(conn);
+
+    let outcomes = state
+        .writer
+        .write(Some(&emitter.description), points)
+        .await?;
+    let response = WebhookResponse {
+        accepted: outcomes.len(),
+        duplicates: outcomes
+            .iter()
+            .filter(|outcome| **outcome == InsertOutcome::Duplicate)
+            .count(),
+    };
+
+    info!(
+        message = "Received webhook",
+        webhook = %name,
+        accepted = response.accepted,
+        duplicates = response.duplicates
+    );
+
+    Ok(Json(response))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_webhooks(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+) -> Result<Json<Vec<WebhookResponseConfig>>, AppError> {
+    let conn = state.connection.lock().await;
+
+    let mut stmt = conn.prepare("SELECT emitter, CAST(config as Text) FROM webhooks;")?;
+    let response: Result<Vec<(String, String)>, _> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect();
+
+    let response = response?
+        .into_iter()
+        .map(|(emitter, config)| {
+            Ok(WebhookResponseConfig {
+                emitter,
+                config: serde_json::from_str(&config)?,
+            })
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    Ok(Json(response))
+}
+
+/// Create a webhook together with the emitter whose token it accepts
+#[tracing::instrument(skip_all, fields( webhook = %request.name))]
+pub async fn add_webhook(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<WebhookConfig>,
+) -> Result<Json<AddWebhookResponse>, AppError> {
+    request.mapping.check()?;
+
+    let mut conn = state.connection.lock().await;
+    if webhook(&conn, &request.name)?.is_some() {
+        error!(message = "webhook already exists");
+        return Err(AppError::Status(StatusCode::BAD_REQUEST));
+    }
+
+    // The emitter name may also be taken by an emitter created by hand
+    let emitter = format!("webhook:{}", request.name);
+    let emitter_exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM emitters WHERE description = (?));",
+        params![emitter],
+        |row| row.get(0),
+    )?;
+    if emitter_exists {
+        error!(message = "webhook emitter already exists", emitter);
+        return Err(AppError::Status(StatusCode::BAD_REQUEST));
+    }
+
+    let token = get_auth_token();
+
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO emitters (token, description, allowed_buckets) VALUES (?, ?, ?);",
+        params![token, emitter, serde_json::to_string(&[&request.bucket])?],
+    )?;
+    tx.execute(
+        "INSERT INTO webhooks (name, emitter, config) VALUES (?, ?, ?);",
+        params![request.name, emitter, serde_json::to_string(&request)?],
+    )?;
+    tx.commit()?;
+
+    Ok(Json(AddWebhookResponse {
+        name: request.name,
+        emitter,
+        token,
+    }))
+}
+
+#[tracing::instrument(skip_all, fields( webhook = %request.name))]
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    _: AuthenticatedUser,
+    Json(request): Json<DeleteWebhookRequest>,
+) -> Result<StatusCode, AppError> {
+    let mut conn = state.connection.lock().await;
+
+    let Some((emitter, _)) = webhook(&conn, &request.name)? else {
+        return Ok(StatusCode::NO_CONTENT);
+    };
+
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM webhooks WHERE name = (?);",
+        params![request.name],
+    )?;
+    tx.execute(
+        "DELETE FROM emitters WHERE description = (?);",
+        params![emitter],
+    )?;
+    tx.commit()?;
+
+    info!(message = "Deleted webhook", emitter);
+
+    Ok(StatusCode::OK)
+}
+
+/// Emitter description and configuration of the named webhook
+fn webhook(conn: &Connection, name: &str) -> Result<Option<(String, WebhookConfig)>, AppError> {
+    let mut stmt = conn
+        .prepare_cached("SELECT emitter, CAST(config as Text) FROM webhooks WHERE name = (?);")?;
+    let mut rows = stmt.query(params![name])?;
+
+    let Some(row) = rows.next()? else {
+        return Ok(None);
+    };
+    let config: String = row.get(1)?;
+
+    Ok(Some((row.get(0)?, serde_json::from_str(&config)?)))
+}
+
+/// Check a hex encoded HMAC-SHA256 of the body, optionally prefixed with
+/// `sha256=` as sent by GitHub and others
+fn verify_signature(secret: &str, signature: Option<&str>, body: &[u8]) -> Result<(), AppError> {
+    let invalid = || {
+        error!(message = "Invalid webhook signature");
+        AppError::Status(StatusCode::UNAUTHORIZED)
+    };
+
+    let signature = signature.ok_or_else(invalid)?;
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let signature = hex::decode(signature).map_err(|_| invalid())?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|_| invalid())?;
+    mac.update(body);
+    mac.verify_slice(&signature).map_err(|_| invalid())
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct WebhookConfig {
+    name: String,
+    bucket: String,
+    #[serde(flatten)]
+    mapping: JsonMapping,
+    hmac_secret: Option<String>,
+    // header carrying the signature, `X-Signature` if not set
+    hmac_header: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct WebhookResponseConfig {
+    // emitter whose token the webhook accepts
+    emitter: String,
+    #[serde(flatten)]
+    config: WebhookConfig,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AddWebhookResponse {
+    name: String,
+    emitter: String,
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteWebhookRequest {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookResponse {
+    accepted: usize,
+    // accepted points that were already stored
+    duplicates: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "webhook-secret";
+    const BODY: &[u8] = br#"{"temperature": 21.5}"#;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn is_unauthorized(result: Result<(), AppError>) -> bool {
+        matches!(result, Err(AppError::Status(StatusCode::UNAUTHORIZED)))
+    }
+
+    #[test]
+    fn accepts_valid_signatures() {
+        let signature = sign(SECRET, BODY);
+
+        assert!(verify_signature(SECRET, Some(&signature), BODY).is_ok());
+        let prefixed = format!("sha256={}", signature);
+        assert!(verify_signature(SECRET, Some(&prefixed), BODY).is_ok());
+        let uppercase = signature.to_uppercase();
+        assert!(verify_signature(SECRET, Some(&uppercase), BODY).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_signatures() {
+        let signature = sign(SECRET, BODY);
+
+        let tampered_body = br#"{"temperature": 31.5}"#;
+        assert!(is_unauthorized(verify_signature(
+            SECRET,
+            Some(&signature),
+            tampered_body
+        )));
+
+        let other_secret = sign("other-secret", BODY);
+        assert!(is_unauthorized(verify_signature(
+            SECRET,
+            Some(&other_secret),
+            BODY
+        )));
+
+        let truncated = &signature[..signature.len() - 2];
+        assert!(is_unauthorized(verify_signature(
+            SECRET,
+            Some(truncated),
+            BODY
+        )));
+        assert!(is_unauthorized(verify_signature(
+            SECRET,
+            Some("not hex"),
+            BODY
+        )));
+    }
+
+    #[test]
+    fn rejects_missing_signatures() {
+        assert!(is_unauthorized(verify_signature(SECRET, None, BODY)));
+        assert!(is_unauthorized(verify_signature(SECRET, Some(""), BODY)));
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    env, slice,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::StatusCode;
+use duckdb::{params, Connection};
+use jiff::{tz::TimeZone, Timestamp};
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    time::sleep,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    error::AppError,
+    ingest::{is_duplicate, InsertOutcome, Point},
+    limits::{count_violation, remaining_points, seconds_until_quota_reset, Violation},
+};
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+// Write requests waiting for the writer before handlers have to wait to enqueue
+const QUEUE_CAPACITY: usize = 1024;
+
+pub struct WriterConfig {
+    // points after which a batch is written without waiting for the flush interval
+    batch_size: usize,
+    // time the first request of a batch waits for others to join it
+    flush_interval: Duration,
+    // treat points with identical bucket, timestamp and payload as duplicates
+    dedupe_identical: bool,
+}
+
+impl WriterConfig {
+    /// Batching is configured in `WRITER_BATCH_SIZE` points and
+    /// `WRITER_FLUSH_INTERVAL_MS` milliseconds
+    pub fn from_env(dedupe_identical: bool) -> Self {
+        WriterConfig {
+            batch_size: env::var("WRITER_BATCH_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_BATCH_SIZE),
+            flush_interval: env::var("WRITER_FLUSH_INTERVAL_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_FLUSH_INTERVAL),
+            dedupe_identical,
+        }
+    }
+}
+
+struct WriteRequest {
+    // description of the emitter that sent the points, counted in its activity
+    emitter: Option<String>,
+    points: Vec<Point>,
+    ack: oneshot::Sender<Result<Vec<InsertOutcome>, WriteError>>,
+}
+
+enum WriteError {
+    // the points would exceed the emitter's daily quota
+    QuotaExceeded,
+    Failed(String),
+}
+
+/// Handle to the task that owns all writes to the `timeseries` table
+#[derive(Clone)]
+pub struct Writer {
+    sender: mpsc::Sender<WriteRequest>,
+}
+
+impl Writer {
+    /// Start the writer task on its own connection to the database of
+    /// `connection`, so queries don't wait for batches to be written
+    pub fn spawn(connection: &Connection, config: WriterConfig) -> Result<Self, AppError> {
+        let connection = Arc::new(Mutex::new(connection.try_clone()?));
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run_writer(receiver, connection, config));
+
+        Ok(Writer { sender })
+    }
+
+    /// Queue points and wait until they are committed. Returns one outcome per
+    /// point.
+    pub async fn write(
+        &self,
+        emitter: Option<&str>,
+        points: Vec<Point>,
+    ) -> Result<Vec<InsertOutcome>, AppError> {
+        if points.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let (ack, receiver) = oneshot::channel();
+        let request = WriteRequest {
+            emitter: emitter.map(|emitter| emitter.to_string()),
+            points,
+            ack,
+        };
+        self.sender.send(request).await.map_err(|_| {
+            error!(message = "Writer task is not running");
+            AppError::Status(StatusCode::SERVICE_UNAVAILABLE)
+        })?;
+
+        match receiver.await {
+            Ok(Ok(outcomes)) => Ok(outcomes),
+            Ok(Err(WriteError::QuotaExceeded)) => {
+                Err(AppError::RateLimited(seconds_until_quota_reset()))
+            }
+            Ok(Err(WriteError::Failed(e))) => {
+                error!(message = "Failed to write points", error = %e);
+                Err(AppError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+            }
+            Err(_) => {
+                error!(message = "Writer task dropped write request");
+                Err(AppError::Status(StatusCode::SERVICE_UNAVAILABLE))
+            }
+        }
+    }
+}
+
+async fn run_writer(
+    mut receiver: mpsc::Receiver<WriteRequest>,
+    connection: Arc<Mutex<Connection>>,
+    config: WriterConfig,
+) {
+    while let Some(request) = receiver.recv().await {
+        let mut size = request.points.len();
+        let mut requests = vec![request];
+
+        let deadline = sleep(config.flush_interval);
+        tokio::pin!(deadline);
+        while size < config.batch_size {
+            tokio::select! {
+                request = receiver.recv() => match request {
+                    Some(request) => {
+                        size += request.points.len();
+                        requests.push(request);
+                    }
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+
+        // DuckDB calls block, keep them off the async worker threads
+        let connection = connection.clone();
+        let dedupe_identical = config.dedupe_identical;
+        let written = tokio::task::spawn_blocking(move || {
+            let mut conn = connection.blocking_lock();
+            write_requests(&mut conn, dedupe_identical, requests);
+        })
+        .await;
+
+        if let Err(e) = written {
+            error!(message = "Writer batch panicked", error = %e);
+        }
+    }
+
+    info!(message = "Writer task stopped");
+}
+
+/// Write the batched requests and acknowledge them. If the batch fails, its
+/// requests are retried one by one so a bad point only fails its own request.
+fn write_requests(conn: &mut Connection, dedupe_identical: bool, requests: Vec<WriteRequest>) {
+    let requests = reject_over_quota(conn, requests);
+    if requests.is_empty() {
+        return;
+    }
+
+    match write_batch(conn, dedupe_identical, &requests) {
+        Ok(outcomes) => {
+            for (request, outcomes) in requests.into_iter().zip(outcomes) {
+                // The handler is gone if its client disconnected
+                let _ = request.ack.send(Ok(outcomes));
+            }
+        }
+        Err(e) if requests.len() == 1 => {
+            for request in requests {
+                let _ = request.ack.send(Err(WriteError::Failed(e.to_string())));
+            }
+        }
+        Err(e) => {
+            warn!(
+                message = "Batch failed, writing requests separately",
+                requests = requests.len(),
+                error = %e
+            );
+            for request in requests {
+                let result = write_batch(conn, dedupe_identical, slice::from_ref(&request))
+                    .map(|mut outcomes| outcomes.remove(0))
+                    .map_err(|e| WriteError::Failed(e.to_string()));
+                let _ = request.ack.send(result);
+            }
+        }
+    }
+}
+
+/// Acknowledge the requests whose points exceed what is left of their
+/// emitter's daily quota and return the others. Requests use up the quota in
+/// the order they were queued.
+fn reject_over_quota(conn: &Connection, requests: Vec<WriteRequest>) -> Vec<WriteRequest> {
+    let mut remaining: HashMap<String, Option<i64>> = HashMap::new();
+    let mut accepted = Vec::with_capacity(requests.len());
+
+    for request in requests {
+        let Some(emitter) = request.emitter.clone() else {
+            accepted.push(request);
+            continue;
+        };
+
+        let left = match remaining.entry(emitter.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match remaining_points(conn, &emitter) {
+                Ok(left) => entry.insert(left),
+                Err(e) => {
+                    let _ = request.ack.send(Err(WriteError::Failed(e.to_string())));
+                    continue;
+                }
+            },
+        };
+
+        let points = request.points.len() as i64;
+        match left {
+            Some(left) if points > *left => {
+                if let Err(e) = count_violation(conn, &emitter, Violation::QuotaExceeded) {
+                    error!(message = "Failed to count quota violation", error = %e);
+                }
+                let _ = request.ack.send(Err(WriteError::QuotaExceeded));
+            }
+            Some(left) => {
+                *left -= points;
+                accepted.push(request);
+            }
+            None => accepted.push(request),
+        }
+    }
+
+    accepted
+}
+
+/// Append the points of all requests in one transaction, so a failing batch
+/// is rejected as a whole and nothing is acknowledged before it is committed
+fn write_batch(
+    conn: &mut Connection,
+    dedupe_identical: bool,
+    requests: &[WriteRequest],
+) -> Result<Vec<Vec<InsertOutcome>>, AppError> {
+    let tx = conn.transaction()?;
+
+    let mut outcomes = Vec::with_capacity(requests.len());
+    {
+        // Appended rows aren't visible to queries before the flush, so
+        // duplicates within the batch are tracked separately
+        let mut keys = HashSet::new();
+        let mut identical = HashSet::new();
+
+        let mut appender = tx.appender("timeseries")?;
+        for request in requests.iter() {
+            let mut request_outcomes = Vec::with_capacity(request.points.len());
+            for point in request.points.iter() {
+                let payload = serde_json::to_string(&point.payload)?;
+
+                let pending = match &point.idempotency_key {
+                    Some(key) => !keys.insert((point.bucket.as_str(), key.as_str())),
+                    None if dedupe_identical => !identical.insert((
+                        point.bucket.as_str(),
+                        point.timestamp.as_str(),
+                        payload.clone(),
+                    )),
+                    None => false,
+                };
+                if pending || is_duplicate(&tx, dedupe_identical, point, &payload)? {
+                    request_outcomes.push(InsertOutcome::Duplicate);
+                    continue;
+                }
+
+                appender.append_row(params![
+                    point.timestamp,
+                    point.bucket,
+                    payload,
+                    point.idempotency_key,
+                    point.original_timestamp
+                ])?;
+                request_outcomes.push(InsertOutcome::Inserted);
+            }
+            outcomes.push(request_outcomes);
+        }
+        appender.flush()?;
+    }
+
+    // Count stored points per emitter and bucket for the day they were written
+    let mut writes: HashMap<(&str, &str), i64> = HashMap::new();
+    for (request, request_outcomes) in requests.iter().zip(outcomes.iter()) {
+        let Some(emitter) = &request.emitter else {
+            continue;
+        };
+        for (point, outcome) in request.points.iter().zip(request_outcomes) {
+            if *outcome == InsertOutcome::Inserted {
+                *writes
+                    .entry((emitter.as_str(), point.bucket.as_str()))
+                    .or_default() += 1;
+            }
+        }
+    }
+    let today = Timestamp::now().to_zoned(TimeZone::UTC).date().to_string();
+    for ((emitter, bucket), count) in writes {
+        tx.prepare_cached(
+            "INSERT INTO emitter_activity (emitter, day, bucket, writes) VALUES (?, CAST((?) as DATE), ?, ?) ON CONFLICT (emitter, day, bucket) DO UPDATE SET writes = emitter_activity.writes + excluded.writes;",
+        )?
+        .execute(params![emitter, today, bucket, count])?;
+    }
+
+    tx.commit()?;
+
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::migration::apply_migrations;
+
+    async fn test_writer() -> (Writer, Arc<Mutex<Connection>>) {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        apply_migrations(conn.clone()).await.unwrap();
+
+        // Long enough for concurrent requests to end up in the same batch
+        let config = WriterConfig {
+            batch_size: DEFAULT_BATCH_SIZE,
+            flush_interval: Duration::from_millis(200),
+            dedupe_identical: false,
+        };
+        let writer = Writer::spawn(&*conn.lock().await, config).unwrap();
+
+        (writer, conn)
+    }
+
+    fn point(timestamp: &str, idempotency_key: Option<&str>) -> Point {
+        Point {
+            timestamp: timestamp.into(),
+            bucket: "co2".into(),
+            payload: json!({ "co2": 612 }),
+            idempotency_key: idempotency_key.map(|key| key.into()),
+            original_timestamp: None,
+        }
+    }
+
+    async fn stored_points(conn: &Arc<Mutex<Connection>>) -> i64 {
+        conn.lock()
+            .await
+            .query_row("SELECT count(*) FROM timeseries;", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn skips_duplicate_idempotency_keys() {
+        let (writer, conn) = test_writer().await;
+
+        let outcomes = writer
+            .write(
+                None,
+                vec![
+                    point("2024-10-01T06:00:00Z", Some("a")),
+                    point("2024-10-01T06:00:00Z", Some("a")),
+                    point("2024-10-01T06:00:00Z", None),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            outcomes,
+            [
+                InsertOutcome::Inserted,
+                InsertOutcome::Duplicate,
+                InsertOutcome::Inserted
+            ]
+        );
+
+        let outcomes = writer
+            .write(None, vec![point("2024-10-01T07:00:00Z", Some("a"))])
+            .await
+            .unwrap();
+        assert_eq!(outcomes, [InsertOutcome::Duplicate]);
+        assert_eq!(stored_points(&conn).await, 2);
+    }
+
+    #[tokio::test]
+    async fn failing_points_only_fail_their_request() {
+        let (writer, conn) = test_writer().await;
+
+        let (valid, invalid, other) = tokio::join!(
+            writer.write(None, vec![point("2024-10-01T06:00:00Z", None)]),
+            writer.write(
+                None,
+                vec![
+                    point("2024-10-01T06:00:05Z", None),
+                    point("yesterday", None)
+                ]
+            ),
+            writer.write(None, vec![point("2024-10-01T06:00:10Z", None)]),
+        );
+
+        assert_eq!(valid.unwrap(), [InsertOutcome::Inserted]);
+        assert!(invalid.is_err());
+        assert_eq!(other.unwrap(), [InsertOutcome::Inserted]);
+        // Nothing of the failed request is stored
+        assert_eq!(stored_points(&conn).await, 2);
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_daily_quota_are_rejected() {
+        let (writer, conn) = test_writer().await;
+        conn.lock()
+            .await
+            .execute(
+                "INSERT INTO emitters (token, description, max_points_per_day) VALUES (?, ?, ?);",
+                params!["token", "sensor", 3],
+            )
+            .unwrap();
+        let points = |count: usize| {
+            (0..count)
+                .map(|i| point(&format!("2024-10-01T06:00:0{}Z", i), None))
+                .collect::<Vec<_>>()
+        };
+
+        // The requests share a batch and use up the quota in order
+        let (first, second, third) = tokio::join!(
+            writer.write(Some("sensor"), points(2)),
+            writer.write(Some("sensor"), points(2)),
+            writer.write(Some("sensor"), points(1)),
+        );
+
+        assert_eq!(first.unwrap().len(), 2);
+        assert!(matches!(second, Err(AppError::RateLimited(_))));
+        assert_eq!(third.unwrap().len(), 1);
+        assert_eq!(stored_points(&conn).await, 3);
+
+        let fourth = writer.write(Some("sensor"), points(1)).await;
+        assert!(matches!(fourth, Err(AppError::RateLimited(_))));
+    }
+}